@@ -5,7 +5,17 @@ use esp32_nimble::uuid128;
 use esp_idf_svc::eth::{BlockingEth, EspEth, EthDriver, SpiEth};
 use esp_idf_svc::hal::gpio::{Gpio18, Gpio19, Input, Output, PinDriver};
 use esp_idf_svc::hal::spi;
-use shared::messages::scanner::ScannerMessage;
+use shared::messages::pattern::Pattern;
+use shared::messages::scanner::{
+    capability, ScannerContent, ScannerMessage, ScannerWrapped, UpdateState, UpdateStatus,
+    PROTOCOL_VERSION,
+};
+
+const BATCH_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const REGISTER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+// Server pings every few seconds, silence this long means it lost us
+const SERVER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
 
 pub struct Application<'a> {
     //pub button: PinDriver<'a, Gpio2, Input>,
@@ -14,16 +24,115 @@ pub struct Application<'a> {
     //pub eth: BlockingEth<EspEth<'a, SpiEth<spi::SpiDriver<'a>>>>,
     pub ip: Option<std::net::Ipv4Addr>,
     pub server_address: Option<std::net::SocketAddr>,
+    // Server registered to without Hello, for scanners behind a router
+    pub server_configured: Option<std::net::SocketAddr>,
+    pub last_register: Option<std::time::Instant>,
+    pub last_server: Option<std::time::Instant>,
     pub socket: Option<std::net::UdpSocket>,
     //pub broadcast: Option<std::net::UdpSocket>,
     pub mac: Vec<u8>,
+    // Pre-shared key for signed messages
+    pub key: Option<Vec<u8>>,
+    // X25519 keys for encrypted messages
+    pub secret: Option<Vec<u8>>,
+    pub server_public: Option<Vec<u8>>,
     pub running: bool,
     pub scan: bool,
+    // Scan results waiting for send with the capture time
+    pub batch: Vec<(std::time::Instant, shared::messages::scanner::ScanDevice)>,
+    pub batch_size: usize,
+    // Output patterns set by server with their start, None is off
+    pub buzzer_pattern: Option<(Pattern, std::time::Instant)>,
+    pub led_pattern: Option<(Pattern, std::time::Instant)>,
+    // End of identify blinking
+    pub identify: Option<std::time::Instant>,
+    // Health counters reported in Status
+    pub started: std::time::Instant,
+    pub scans: u64,
+    pub dropped: u64,
+    pub status_sent: Option<std::time::Instant>,
+    // Firmware update requested by server as url, sha256 and version
+    pub update: Option<(String, Vec<u8>, String)>,
+    // Boot counter in upper half keeps signed messages monotonic over restarts
+    pub sequence: std::sync::atomic::AtomicU64,
+    // Allow-list from server and advertisements not matching it since the last sample
+    pub filter: Option<shared::messages::scanner::Filter>,
+    pub unmatched: u32,
+    // Parts of split allow-list received so far
+    pub filter_parts: Vec<shared::messages::scanner::Filter>,
+    // Radio parameters applied by the scan thread on its next round
+    pub radio: shared::messages::scanner::Radio,
 }
 
 unsafe impl<'a> Sync for Application<'a> {}
 
 impl<'a> Application<'a> {
+    fn capabilities(&self) -> u32 {
+        let mut capabilities = capability::BUZZER
+            | capability::LED
+            | capability::SCAN
+            | capability::BATCHING
+            | capability::ACK
+            | capability::PING
+            | capability::RESTART
+            | capability::IDENTIFY
+            | capability::UPDATE
+            | capability::FILTER
+            | capability::RADIO
+            | capability::PATTERN
+            | capability::CLOCK;
+        if self.key.is_some() {
+            capabilities |= capability::SIGNING | capability::SEQUENCE;
+        }
+        if self.secret.is_some() {
+            capabilities |= capability::ENCRYPTION;
+        }
+        capabilities
+    }
+
+    // Milliseconds since boot, server estimates its offset from TimedPong
+    fn clock(&self, instant: std::time::Instant) -> u64 {
+        instant.duration_since(self.started).as_millis() as u64
+    }
+
+    fn encode(&self, msg: &ScannerMessage) -> anyhow::Result<Vec<u8>> {
+        let wrapped = match (self.key.as_ref(), self.server_public.as_ref()) {
+            (Some(key), _) => {
+                let sequence = self
+                    .sequence
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                    + 1;
+                ScannerWrapped::sequenced(msg, key, sequence)?
+            }
+            (None, Some(_)) => ScannerWrapped::plain(msg)?,
+            (None, None) => return Ok(rmp_serde::to_vec(msg)?),
+        };
+
+        if let Some(server_public) = self.server_public.as_ref() {
+            Ok(wrapped.encrypt(server_public)?.to_vec()?)
+        } else {
+            Ok(wrapped.to_vec()?)
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<ScannerMessage> {
+        let mut wrapped = ScannerWrapped::from_slice(data)?;
+        let encrypted = wrapped.is_encrypted();
+        if let Some(secret) = self.secret.as_ref() {
+            wrapped = wrapped.decrypt(secret)?;
+        }
+        let msg = wrapped.open(self.key.as_deref())?;
+
+        // Only Hello may come unsigned or unencrypted, it is broadcasted
+        if msg.content != ScannerContent::Hello
+            && ((self.key.is_some() && wrapped.is_plain()) || (self.secret.is_some() && !encrypted))
+        {
+            anyhow::bail!("Unsigned or unencrypted message");
+        }
+
+        Ok(msg)
+    }
+
     pub fn process(&mut self) -> anyhow::Result<()> {
         let mut buffer: [u8; 1024] = [0u8; 1024];
 
@@ -35,56 +144,178 @@ impl<'a> Application<'a> {
             self.socket = Some(socket);
         }
 
+        if let Some(configured) = self.server_configured {
+            if self
+                .last_server
+                .map_or(true, |last| last.elapsed() >= SERVER_TIMEOUT)
+                && self
+                    .last_register
+                    .map_or(true, |last| last.elapsed() >= REGISTER_INTERVAL)
+            {
+                self.last_register = Some(std::time::Instant::now());
+                self.server_address = Some(configured);
+                if let Err(err) = self.register(configured) {
+                    log::error!("Unable to register: {:?}", err);
+                }
+            }
+        }
+
+        if self
+            .status_sent
+            .map_or(true, |sent| sent.elapsed() >= STATUS_INTERVAL)
+        {
+            self.status_sent = Some(std::time::Instant::now());
+            if let Err(err) = self.status() {
+                log::error!("Unable to send status: {:?}", err);
+            }
+        }
+
+        // Do not keep results longer than one main loop cycle
+        if self
+            .batch
+            .first()
+            .is_some_and(|(captured, _)| captured.elapsed() >= BATCH_TIMEOUT)
+        {
+            self.flush();
+        }
+
         if let Some(socket) = self.socket.as_ref() {
             // If there is a communication with server
             // Check for new server message (config, ping)
 
             if let Ok((len, server_address)) = socket.recv_from(&mut buffer) {
-                if let Ok(msg) = rmp_serde::from_slice::<shared::messages::scanner::ScannerMessage>(
-                    &buffer[0..len],
-                ) {
+                if let Ok(msg) = self.decode(&buffer[0..len]) {
                     log::info!("Received message: {:?}", msg);
+                    self.last_server = Some(std::time::Instant::now());
 
                     match msg.content {
                         shared::messages::scanner::ScannerContent::Hello => {
-                            let register_msg =
-                                shared::messages::scanner::ScannerContent::Register {
-                                    mac: self.mac.clone(),
-                                };
-                            let msg = ScannerMessage {
-                                uuid: uuid::Uuid::new_v4(),
-                                content: register_msg,
-                            };
-                            let register_data = rmp_serde::to_vec(&msg)?;
                             self.server_address = Some(server_address);
-                            log::info!("{:?}", msg);
-
-                            socket.send_to(&register_data, server_address)?;
+                            self.register(server_address)?;
                         }
 
                         shared::messages::scanner::ScannerContent::Set(set) => {
+                            // Output without pattern is continuous
+                            let now = std::time::Instant::now();
                             if let Some(buzzer) = set.buzzer {
-                                if buzzer {
-                                    self.buzzer.set_high();
-                                } else {
-                                    self.buzzer.set_low();
-                                }
+                                self.buzzer_pattern = buzzer
+                                    .then(|| (set.buzzer_pattern.clone().unwrap_or_default(), now));
                             }
 
                             if let Some(led) = set.led {
-                                if led {
-                                    self.led.set_high();
-                                } else {
-                                    self.led.set_low();
-                                }
+                                self.led_pattern =
+                                    led.then(|| (set.led_pattern.clone().unwrap_or_default(), now));
                             }
 
                             if let Some(scan) = set.scan {
                                 self.scan = scan;
                             }
 
+                            if let Some(radio) = set.radio {
+                                self.radio = radio.merge(&self.radio);
+                                log::info!("Radio: {:?}", self.radio);
+                            }
+
+                            // Confirm the new state to server
+                            let ok_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Ok(msg.uuid),
+                            };
+                            socket.send_to(&self.encode(&ok_msg)?, server_address)?;
+
                             log::info!("Setting message");
                         }
+                        shared::messages::scanner::ScannerContent::Identify(seconds) => {
+                            self.identify = Some(
+                                std::time::Instant::now()
+                                    + std::time::Duration::from_secs(seconds as u64),
+                            );
+
+                            let ok_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Ok(msg.uuid),
+                            };
+                            socket.send_to(&self.encode(&ok_msg)?, server_address)?;
+                        }
+                        shared::messages::scanner::ScannerContent::Update {
+                            url,
+                            sha256,
+                            version,
+                        } => {
+                            // Download starts after confirmation, it blocks the loop
+                            self.update = Some((url, sha256, version));
+
+                            let ok_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Ok(msg.uuid),
+                            };
+                            socket.send_to(&self.encode(&ok_msg)?, server_address)?;
+                        }
+                        shared::messages::scanner::ScannerContent::Filter(filter) => {
+                            let filter = match filter.part.clone() {
+                                None => Some(filter),
+                                Some(part) => {
+                                    // Older revision is dropped, retransmitted part is kept once
+                                    self.filter_parts.retain(|p| {
+                                        p.part.as_ref().is_some_and(|p| {
+                                            p.revision == part.revision && p.index != part.index
+                                        })
+                                    });
+                                    self.filter_parts.push(filter);
+                                    let filter = shared::messages::scanner::Filter::assemble(
+                                        &self.filter_parts,
+                                    );
+                                    if filter.is_some() {
+                                        self.filter_parts.clear();
+                                    }
+                                    filter
+                                }
+                            };
+                            if let Some(filter) = filter {
+                                log::info!(
+                                    "Filter of {} devices, {} prefixes, {} services",
+                                    filter.macs.len(),
+                                    filter.prefixes.len(),
+                                    filter.services.len()
+                                );
+                                self.filter = Some(filter);
+                            }
+
+                            let ok_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Ok(msg.uuid),
+                            };
+                            socket.send_to(&self.encode(&ok_msg)?, server_address)?;
+                        }
+                        shared::messages::scanner::ScannerContent::Restart => {
+                            // Confirm before restart, otherwise server retransmits
+                            let ok_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Ok(msg.uuid),
+                            };
+                            socket.send_to(&self.encode(&ok_msg)?, server_address)?;
+
+                            log::info!("Restarting");
+                            esp_idf_svc::hal::reset::restart();
+                        }
+                        shared::messages::scanner::ScannerContent::Ping(payload) => {
+                            let pong_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Pong(payload),
+                            };
+                            socket.send_to(&self.encode(&pong_msg)?, server_address)?;
+                        }
+                        // Only server which understands TimedPong sends TimedPing
+                        shared::messages::scanner::ScannerContent::TimedPing(payload) => {
+                            let pong_msg = ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::TimedPong {
+                                    payload,
+                                    time: self.clock(std::time::Instant::now()),
+                                },
+                            };
+                            socket.send_to(&self.encode(&pong_msg)?, server_address)?;
+                        }
                         _ => {
                             log::info!("Unexpected message: {:?}", msg);
                         }
@@ -93,21 +324,255 @@ impl<'a> Application<'a> {
             }
         }
 
+        if let Some((url, sha256, version)) = self.update.take() {
+            if let Err(err) = self.install(&url, &sha256, &version) {
+                log::error!("Firmware update failed: {:?}", err);
+                self.update_status(&version, UpdateState::Failed(err.to_string()), 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Drive outputs by their patterns, called often from the output thread
+    pub fn signal(&mut self) {
+        let now = std::time::Instant::now();
+
+        let buzzer = Self::level(&mut self.buzzer_pattern, now);
+        let _ = if buzzer {
+            self.buzzer.set_high()
+        } else {
+            self.buzzer.set_low()
+        };
+
+        let led = match self.identify {
+            // Three short flashes every second until identify ends, then restore LED
+            Some(until) if now < until => {
+                let slot = (until - now).as_millis() / 100 % 10;
+                slot < 6 && slot % 2 == 0
+            }
+            _ => {
+                self.identify = None;
+                Self::level(&mut self.led_pattern, now)
+            }
+        };
+        let _ = if led {
+            self.led.set_high()
+        } else {
+            self.led.set_low()
+        };
+    }
+
+    // Finished pattern turns the output off
+    fn level(pattern: &mut Option<(Pattern, std::time::Instant)>, now: std::time::Instant) -> bool {
+        let Some((current, started)) = pattern.as_ref() else {
+            return false;
+        };
+
+        match current.level(now.duration_since(*started).as_millis() as u64) {
+            Some(level) => level,
+            None => {
+                *pattern = None;
+                false
+            }
+        }
+    }
+
+    fn register(&self, server_address: std::net::SocketAddr) -> anyhow::Result<()> {
+        let Some(socket) = self.socket.as_ref() else {
+            return Ok(());
+        };
+
+        let msg = ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::Register {
+                mac: self.mac.clone(),
+                protocol: PROTOCOL_VERSION,
+                firmware: env!("CARGO_PKG_VERSION").into(),
+                capabilities: self.capabilities(),
+            },
+        };
+        log::info!("{:?}", msg);
+
+        socket.send_to(&self.encode(&msg)?, server_address)?;
+        Ok(())
+    }
+
+    fn update_status(&self, version: &str, state: UpdateState, progress: u8) {
+        if let (Some(socket), Some(server_address)) =
+            (self.socket.as_ref(), self.server_address.as_ref())
+        {
+            let msg = ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content: ScannerContent::UpdateStatus(UpdateStatus {
+                    version: version.to_string(),
+                    state,
+                    progress,
+                }),
+            };
+            if let Ok(data) = self.encode(&msg) {
+                let _ = socket.send_to(&data, server_address);
+            }
+        }
+    }
+
+    // Download image into the other OTA partition, verify it and boot into it
+    fn install(&self, url: &str, sha256: &[u8], version: &str) -> anyhow::Result<()> {
+        use esp_idf_svc::io::Read;
+        use sha2::Digest;
+
+        let mut connection =
+            esp_idf_svc::http::client::EspHttpConnection::new(&Default::default())?;
+        connection.initiate_request(esp_idf_svc::http::Method::Get, url, &[])?;
+        connection.initiate_response()?;
+        if connection.status() != 200 {
+            anyhow::bail!("Download failed with status {}", connection.status());
+        }
+        let size = connection
+            .header("Content-Length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let mut ota = esp_idf_svc::ota::EspOta::new()?;
+        let mut update = ota.initiate_update()?;
+        let mut hasher = sha2::Sha256::new();
+        let mut buffer = [0u8; 4096];
+        let mut downloaded = 0;
+        let mut reported = 0;
+
+        loop {
+            let len = connection.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..len]);
+            update.write(&buffer[..len])?;
+            downloaded += len;
+
+            let progress = if size > 0 {
+                (downloaded * 100 / size).min(100) as u8
+            } else {
+                0
+            };
+            if progress >= reported + 10 {
+                reported = progress;
+                self.update_status(version, UpdateState::Downloading, progress);
+            }
+        }
+
+        self.update_status(version, UpdateState::Verifying, 100);
+        if hasher.finalize().as_slice() != sha256 {
+            update.abort()?;
+            anyhow::bail!("Firmware checksum mismatch");
+        }
+
+        self.update_status(version, UpdateState::Installing, 100);
+        update.complete()?;
+
+        log::info!("Restarting into firmware {}", version);
+        esp_idf_svc::hal::reset::restart();
+    }
+
+    fn status(&self) -> anyhow::Result<()> {
+        let (Some(socket), Some(server_address)) =
+            (self.socket.as_ref(), self.server_address.as_ref())
+        else {
+            return Ok(());
+        };
+
+        let msg = ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::Status(shared::messages::scanner::Status {
+                uptime: self.started.elapsed().as_secs(),
+                firmware: env!("CARGO_PKG_VERSION").into(),
+                free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                scans: self.scans,
+                dropped: self.dropped,
+                link: self.ip.is_some(),
+                state: shared::messages::scanner::State {
+                    scan: Some(self.scan),
+                    led: Some(self.led_pattern.is_some()),
+                    buzzer: Some(self.buzzer_pattern.is_some()),
+                    radio: Some(self.radio.clone()),
+                },
+            }),
+        };
+        socket.send_to(&self.encode(&msg)?, server_address)?;
         Ok(())
     }
 
     pub fn report(&mut self, scan_device: shared::messages::scanner::ScanDevice) {
         //log::info!("Scan: {:?}", scan_device);
+        self.scans += 1;
+
+        if self
+            .radio
+            .min_rssi
+            .is_some_and(|min_rssi| scan_device.rssi < min_rssi)
+        {
+            return;
+        }
+
+        // Unknown devices only as a sample, server still discovers them
+        if let Some(filter) = self.filter.as_ref() {
+            if !filter.matches(&scan_device.mac, &scan_device.data) {
+                self.unmatched += 1;
+                if filter.sample == 0 || self.unmatched < filter.sample {
+                    return;
+                }
+                self.unmatched = 0;
+            }
+        }
+
+        let size = rmp_serde::to_vec(&scan_device)
+            .map(|d| d.len())
+            .unwrap_or(0);
+        if self.batch_size + size > shared::messages::scanner::SCAN_BATCH_SIZE {
+            self.flush();
+        }
+
+        self.batch_size += size;
+        self.batch.push((std::time::Instant::now(), scan_device));
+    }
+
+    // Send collected scan results in one datagram
+    pub fn flush(&mut self) {
+        let started = self.started;
+        let batch: Vec<shared::messages::scanner::ScanDevice> = self
+            .batch
+            .drain(..)
+            .map(
+                |(captured, scan_device)| shared::messages::scanner::ScanDevice {
+                    age: captured.elapsed().as_millis() as u32,
+                    timestamp: Some(captured.duration_since(started).as_millis() as u64),
+                    ..scan_device
+                },
+            )
+            .collect();
+        self.batch_size = 0;
+
+        if batch.is_empty() {
+            return;
+        }
 
         if let (Some(socket), Some(server_address)) =
             (self.socket.as_ref(), self.server_address.as_ref())
         {
+            let count = batch.len() as u64;
             let msg = shared::messages::scanner::ScannerMessage {
-                content: shared::messages::scanner::ScannerContent::ScanResult(scan_device),
+                content: shared::messages::scanner::ScannerContent::ScanResultBatch(batch),
                 uuid: uuid::Uuid::new_v4(),
             };
-            let data = rmp_serde::to_vec(&msg).unwrap();
-            socket.send_to(&data, server_address).unwrap();
+            let sent = self
+                .encode(&msg)
+                .and_then(|data| Ok(socket.send_to(&data, server_address)?));
+            if let Err(err) = sent {
+                log::error!("Unable to send scan results: {:?}", err);
+                self.dropped += count;
+            }
+        } else {
+            self.dropped += batch.len() as u64;
         }
     }
 }
@@ -149,6 +149,15 @@ pub unsafe fn voidp_to_ref<'a, T>(ptr: *mut core::ffi::c_void) -> &'a mut T {
     &mut *ptr.cast()
 }
 
+// Increment persistent boot counter, it prefixes message sequence numbers
+fn boot_count() -> anyhow::Result<u32> {
+    let partition = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
+    let mut nvs = esp_idf_svc::nvs::EspNvs::new(partition, "evac", true)?;
+    let boot = nvs.get_u32("boot")?.unwrap_or(0).wrapping_add(1);
+    nvs.set_u32("boot", boot)?;
+    Ok(boot)
+}
+
 fn init_bluetooth() -> anyhow::Result<()> {
     unsafe {
         let result = esp_idf_svc::sys::nvs_flash_init();
@@ -269,11 +278,39 @@ fn main() -> anyhow::Result<()> {
         //  button,
         ip: None,
         server_address: None,
+        server_configured: option_env!("EVAC_SERVER_ADDRESS").and_then(|addr| addr.parse().ok()),
+        last_register: None,
+        last_server: None,
         socket: None,
         //broadcast: None,
         running: false,
         mac: mac.to_vec(),
+        key: option_env!("EVAC_SCANNER_KEY").and_then(|key| hex::decode(key).ok()),
+        secret: option_env!("EVAC_SCANNER_SECRET").and_then(|key| hex::decode(key).ok()),
+        server_public: option_env!("EVAC_SERVER_PUBLIC").and_then(|key| hex::decode(key).ok()),
         scan: false,
+        batch: Vec::new(),
+        batch_size: 0,
+        // Both outputs stay on after boot until the server sends the state
+        buzzer_pattern: Some((Default::default(), std::time::Instant::now())),
+        led_pattern: Some((Default::default(), std::time::Instant::now())),
+        identify: None,
+        started: std::time::Instant::now(),
+        scans: 0,
+        dropped: 0,
+        status_sent: None,
+        update: None,
+        sequence: std::sync::atomic::AtomicU64::new((boot_count()? as u64) << 32),
+        filter: None,
+        unmatched: 0,
+        filter_parts: Vec::new(),
+        radio: shared::messages::scanner::Radio {
+            interval: Some(100), // 100 * 0.625ms = 62.5ms
+            window: Some(80),    // musí být <= interval
+            active: Some(true),
+            filter_duplicates: Some(true),
+            min_rssi: None,
+        },
     };
 
     log::info!("Starting eth...");
@@ -288,20 +325,31 @@ fn main() -> anyhow::Result<()> {
     application.ip = Some(ip_info.ip);
     application.mac = eth.eth().netif().get_mac().unwrap().to_vec();
     log::info!("IP address: {}", application.ip.unwrap());
+    if let Some(secret) = application.secret.as_ref() {
+        log::info!(
+            "Public key: {}",
+            hex::encode(shared::messages::wrapped::public_key(secret)?)
+        );
+    }
     let mut application = std::sync::Arc::new(std::sync::RwLock::new(application));
 
     let ble_device = esp32_nimble::BLEDevice::take();
     let mut ble_scan = esp32_nimble::BLEScan::new();
 
-    ble_scan.active_scan(true); // active scanning
-    ble_scan.interval(100); // 100 * 0.625ms = 62.5ms
-    ble_scan.window(80); // musí být <= interval
-    ble_scan.filter_duplicates(true);
     let scan_application = application.clone();
     std::thread::spawn(move || {
         let mut scan = false;
+        let mut radio = shared::messages::scanner::Radio::default();
         loop {
-            ble_scan.active_scan(scan);
+            // Server may change radio parameters between scan rounds
+            if let Ok(application) = scan_application.read() {
+                radio = application.radio.clone();
+            }
+            let interval = radio.interval.unwrap_or(100).max(4);
+            ble_scan.interval(interval);
+            ble_scan.window(radio.window.unwrap_or(80).clamp(4, interval));
+            ble_scan.filter_duplicates(radio.filter_duplicates.unwrap_or(true));
+            ble_scan.active_scan(scan && radio.active.unwrap_or(true));
 
             block_on(ble_scan.start(
                 ble_device,
@@ -314,6 +362,8 @@ fn main() -> anyhow::Result<()> {
                             mac: device.addr().as_be_bytes().to_vec(),
                             rssi: device.rssi() as i32,
                             data: data.payload().to_vec(),
+                            age: 0,
+                            timestamp: None,
                         };
 
                         application.report(scan_device);
@@ -327,6 +377,15 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
+    // Patterns need finer timing than the main loop
+    let signal_application = application.clone();
+    std::thread::spawn(move || loop {
+        if let Ok(mut application) = signal_application.write() {
+            application.signal();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    });
+
     loop {
         if let Ok(mut application) = application.write() {
             application.process()?;
@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+
+use shared::messages::{
+    pattern::{Pattern, Signal},
+    scanner::{Radio, State},
+};
+
+use crate::{
+    database::{
+        entities::{self, Alarm, Scanner},
+        Data,
+    },
+    message::web::AlarmInfo,
+};
+
+// Continuous output is the most urgent and endless patterns outlast timed ones
+fn precedence(pattern: &Option<Pattern>) -> (u8, bool, u32) {
+    match pattern {
+        None => (3, true, 0),
+        Some(pattern) => (
+            match pattern.signal {
+                Signal::Continuous => 3,
+                Signal::Sos => 2,
+                Signal::Pulsed { .. } => 1,
+                Signal::Sequence(_) => 0,
+            },
+            pattern.duration == 0,
+            pattern.duration,
+        ),
+    }
+}
+
+// Active alarms covering the scanner with their patterns
+fn active<'a>(
+    data: &'a Data,
+    alarms: &BTreeMap<uuid::Uuid, AlarmInfo>,
+    scanner: &Scanner,
+) -> Vec<(&'a Alarm, Option<&'a entities::Pattern>)> {
+    alarms
+        .values()
+        .filter_map(|info| data.alarms.get(&info.alarm))
+        .filter(|alarm| data.alarm_covers(alarm, scanner))
+        .map(|alarm| {
+            let pattern = alarm.pattern.and_then(|uuid| data.patterns.get(&uuid));
+            (alarm, pattern)
+        })
+        .collect()
+}
+
+// Pattern of the winning alarm turning the output on, None when no alarm does
+fn select(
+    active: &[(&Alarm, Option<&entities::Pattern>)],
+    on: impl Fn(&Alarm) -> bool,
+    output: impl Fn(&entities::Pattern) -> &Pattern,
+) -> Option<Option<Pattern>> {
+    active
+        .iter()
+        .filter(|(alarm, _)| on(alarm))
+        .map(|(alarm, pattern)| (alarm.priority, pattern.map(|p| output(p).clone())))
+        .max_by_key(|(priority, pattern)| (*priority, precedence(pattern)))
+        .map(|(_, pattern)| pattern)
+}
+
+// Buzzer and LED of the scanner resulting from the active alarms, configured outputs stay on
+pub fn output(data: &Data, alarms: &BTreeMap<uuid::Uuid, AlarmInfo>, scanner: &Scanner) -> State {
+    let active = active(data, alarms, scanner);
+    let buzzer = select(&active, |alarm| alarm.buzzer, |pattern| &pattern.buzzer);
+    let led = select(&active, |alarm| alarm.led, |pattern| &pattern.led);
+
+    State {
+        scan: None,
+        buzzer: Some(buzzer.is_some() || scanner.buzzer),
+        led: Some(led.is_some() || scanner.led),
+        buzzer_pattern: buzzer.flatten(),
+        led_pattern: led.flatten(),
+        ..Default::default()
+    }
+}
+
+// Full scanner configuration including alarm outputs
+pub fn state(
+    data: &Data,
+    alarms: &BTreeMap<uuid::Uuid, AlarmInfo>,
+    scanner: &Scanner,
+    defaults: &Radio,
+) -> State {
+    State {
+        scan: Some(scanner.scan),
+        radio: scanner.state(defaults).radio,
+        ..output(data, alarms, scanner)
+    }
+}
+
+// Outputs to re-send to the given scanners
+pub fn outputs(
+    data: &Data,
+    alarms: &BTreeMap<uuid::Uuid, AlarmInfo>,
+    scanners: Vec<uuid::Uuid>,
+) -> Vec<(uuid::Uuid, State)> {
+    scanners
+        .into_iter()
+        .filter_map(|uuid| {
+            data.scanners
+                .get(&uuid)
+                .map(|scanner| (uuid, output(data, alarms, scanner)))
+        })
+        .collect()
+}
+
+// Outputs to re-send after an active alarm was edited, scanners leaving its scope are restored
+pub fn changed(
+    data: &Data,
+    alarms: &BTreeMap<uuid::Uuid, AlarmInfo>,
+    previous: &Alarm,
+) -> Vec<(uuid::Uuid, State)> {
+    if !alarms.values().any(|info| info.alarm == previous.uuid) {
+        return Vec::new();
+    }
+
+    let mut scanners: std::collections::BTreeSet<uuid::Uuid> =
+        data.alarm_scanners(previous).into_iter().collect();
+    if let Some(alarm) = data.alarms.get(&previous.uuid) {
+        scanners.extend(data.alarm_scanners(alarm));
+    }
+    outputs(data, alarms, scanners.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::entities::{Location, Room};
+
+    struct Site {
+        data: Data,
+        scanners: Vec<uuid::Uuid>,
+        fire: uuid::Uuid,
+        drill: uuid::Uuid,
+        light: uuid::Uuid,
+    }
+
+    fn pattern(signal: Signal) -> entities::Pattern {
+        entities::Pattern {
+            uuid: uuid::Uuid::new_v4(),
+            name: String::from("Pattern"),
+            buzzer: Pattern {
+                signal: signal.clone(),
+                duration: 0,
+            },
+            led: Pattern {
+                signal,
+                duration: 0,
+            },
+        }
+    }
+
+    // Two buildings, scanner per room and one unassigned
+    fn site() -> Site {
+        let mut data = Data {
+            scanners: BTreeMap::new(),
+            locations: BTreeMap::new(),
+            rooms: BTreeMap::new(),
+            alarms: BTreeMap::new(),
+            patterns: BTreeMap::new(),
+            ..Default::default()
+        };
+        let locations: Vec<uuid::Uuid> = (0..2).map(|_| uuid::Uuid::new_v4()).collect();
+        for location in &locations {
+            data.locations.insert(
+                *location,
+                Location {
+                    uuid: *location,
+                    name: String::from("Building"),
+                },
+            );
+        }
+
+        let mut scanners = Vec::new();
+        let mut rooms = Vec::new();
+        for location in [locations[0], locations[0], locations[1]] {
+            let room = Room {
+                uuid: uuid::Uuid::new_v4(),
+                location,
+                ..Default::default()
+            };
+            let scanner = Scanner {
+                uuid: uuid::Uuid::new_v4(),
+                room: Some(room.uuid),
+                ..Default::default()
+            };
+            rooms.push(room.uuid);
+            scanners.push(scanner.uuid);
+            data.rooms.insert(room.uuid, room);
+            data.scanners.insert(scanner.uuid, scanner);
+        }
+        let unassigned = uuid::Uuid::new_v4();
+        scanners.push(unassigned);
+        data.scanners.insert(
+            unassigned,
+            Scanner {
+                uuid: unassigned,
+                ..Default::default()
+            },
+        );
+
+        let pulsed = pattern(Signal::Pulsed { on: 500, off: 500 });
+        let sos = pattern(Signal::Sos);
+        let fire_alarm = Alarm {
+            uuid: uuid::Uuid::new_v4(),
+            buzzer: true,
+            led: true,
+            priority: 2,
+            locations: vec![locations[0]],
+            ..Default::default()
+        };
+        let drill_alarm = Alarm {
+            uuid: uuid::Uuid::new_v4(),
+            buzzer: true,
+            led: true,
+            priority: 1,
+            pattern: Some(pulsed.uuid),
+            rooms: vec![rooms[1], rooms[2]],
+            ..Default::default()
+        };
+        let light_alarm = Alarm {
+            uuid: uuid::Uuid::new_v4(),
+            led: true,
+            priority: 1,
+            pattern: Some(sos.uuid),
+            ..Default::default()
+        };
+        data.patterns.insert(pulsed.uuid, pulsed);
+        data.patterns.insert(sos.uuid, sos);
+
+        let (fire, drill, light) = (fire_alarm.uuid, drill_alarm.uuid, light_alarm.uuid);
+        for alarm in [fire_alarm, drill_alarm, light_alarm] {
+            data.alarms.insert(alarm.uuid, alarm);
+        }
+
+        Site {
+            data,
+            scanners,
+            fire,
+            drill,
+            light,
+        }
+    }
+
+    fn activate(alarms: &[uuid::Uuid]) -> BTreeMap<uuid::Uuid, AlarmInfo> {
+        alarms
+            .iter()
+            .map(|alarm| {
+                let info = AlarmInfo {
+                    uuid: uuid::Uuid::new_v4(),
+                    alarm: *alarm,
+                    ..Default::default()
+                };
+                (info.uuid, info)
+            })
+            .collect()
+    }
+
+    // Buzzer and LED of every scanner
+    fn levels(site: &Site, alarms: &BTreeMap<uuid::Uuid, AlarmInfo>) -> Vec<(bool, bool)> {
+        outputs(&site.data, alarms, site.scanners.clone())
+            .into_iter()
+            .map(|(_, state)| (state.buzzer.unwrap(), state.led.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_zones() {
+        let site = site();
+        let mut alarms = activate(&[site.fire, site.drill]);
+
+        assert_eq!(
+            levels(&site, &alarms),
+            vec![(true, true), (true, true), (true, true), (false, false)]
+        );
+        // Fire outranks the drill in the shared room
+        let shared = outputs(&site.data, &alarms, vec![site.scanners[1]]);
+        assert_eq!(shared[0].1.buzzer_pattern, None);
+        let other = outputs(&site.data, &alarms, vec![site.scanners[2]]);
+        assert_eq!(
+            other[0].1.buzzer_pattern.as_ref().map(|p| &p.signal),
+            Some(&Signal::Pulsed { on: 500, off: 500 })
+        );
+
+        // Stopping the fire leaves the drill sounding
+        let fire = *alarms
+            .iter()
+            .find(|(_, info)| info.alarm == site.fire)
+            .unwrap()
+            .0;
+        alarms.remove(&fire);
+        assert_eq!(
+            levels(&site, &alarms),
+            vec![(false, false), (true, true), (true, true), (false, false)]
+        );
+        let shared = outputs(&site.data, &alarms, vec![site.scanners[1]]);
+        assert!(shared[0].1.buzzer_pattern.is_some());
+
+        alarms.clear();
+        assert_eq!(levels(&site, &alarms), vec![(false, false); 4]);
+    }
+
+    #[test]
+    fn site_wide_alarm() {
+        let site = site();
+        let alarms = activate(&[site.light, site.drill]);
+
+        // Only the LED is on everywhere including the unassigned scanner
+        assert_eq!(
+            levels(&site, &alarms),
+            vec![(false, true), (true, true), (true, true), (false, true)]
+        );
+        let states = outputs(&site.data, &alarms, site.scanners.clone());
+        assert_eq!(
+            states[0].1.led_pattern.as_ref().map(|p| &p.signal),
+            Some(&Signal::Sos)
+        );
+        // Both priorities are equal, SOS takes precedence over pulsing
+        assert_eq!(
+            states[1].1.led_pattern.as_ref().map(|p| &p.signal),
+            Some(&Signal::Sos)
+        );
+        assert_eq!(
+            states[1].1.buzzer_pattern.as_ref().map(|p| &p.signal),
+            Some(&Signal::Pulsed { on: 500, off: 500 })
+        );
+    }
+
+    #[test]
+    fn edited_alarm() {
+        let mut site = site();
+        let alarms = activate(&[site.drill]);
+        let previous = site.data.alarms[&site.drill].clone();
+
+        // Drill moves to the first room without its pattern, the old rooms go quiet
+        let drill = site.data.alarms.get_mut(&site.drill).unwrap();
+        drill.rooms = vec![site.data.scanners[&site.scanners[0]].room.unwrap()];
+        drill.pattern = None;
+        let states = changed(&site.data, &alarms, &previous);
+        let levels: BTreeMap<uuid::Uuid, (bool, bool)> = states
+            .iter()
+            .map(|(uuid, state)| (*uuid, (state.buzzer.unwrap(), state.led.unwrap())))
+            .collect();
+        assert_eq!(
+            levels,
+            BTreeMap::from_iter([
+                (site.scanners[0], (true, true)),
+                (site.scanners[1], (false, false)),
+                (site.scanners[2], (false, false)),
+            ])
+        );
+        let (_, first) = states
+            .iter()
+            .find(|(uuid, _)| *uuid == site.scanners[0])
+            .unwrap();
+        assert_eq!(first.buzzer_pattern, None);
+
+        // Inactive alarm changes no scanner
+        assert!(changed(&site.data, &activate(&[site.fire]), &previous).is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use ::server::database::LoadSave;
 use rand::random;
+use server::scanner::capture::{delay, Reader, Record};
 use server::{
     context, database,
     message::web::{AlarmInfo, WebMessage},
@@ -7,7 +8,7 @@ use server::{
 use shared::messages::scanner::{ScanDevice, ScannerMessage};
 use std::{
     collections::BTreeMap,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 use uuid::uuid;
 
@@ -32,6 +33,7 @@ enum Commands {
     Version,
     Notification(Notification),
     DevicePosition(DevicePosition),
+    Replay(Replay),
     Test,
 }
 
@@ -58,6 +60,27 @@ pub struct DevicePosition {
     msg: String,
 }
 
+#[derive(Parser)]
+pub struct Replay {
+    // Capture recorded by server with scannerCapture
+    #[arg(short, long)]
+    file: String,
+
+    // Multiple of real time, 0 replays as fast as possible
+    #[arg(short, long, default_value_t = 1.0)]
+    speed: f64,
+
+    // Scanner port of running server, capture is processed here and activities
+    // and events are printed without it
+    #[arg(
+        short,
+        long,
+        help = "Scanner port of running server, messages are sent unsigned so the server must use \
+                scannerSecurity mixed and the recorded scanners must have no key"
+    )]
+    target: Option<SocketAddr>,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Setup logging
@@ -119,13 +142,17 @@ async fn main() -> anyhow::Result<()> {
             }
         }
         Commands::DevicePosition(device_position) => {
-            let socket =
-                tokio::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
-                    .await?;
-            socket
-                .connect(SocketAddr::V4(config.base.port_scanner))
-                .await
-                .unwrap();
+            let server = *config
+                .base
+                .port_scanner
+                .first()
+                .ok_or(anyhow::anyhow!("No scanner address configured"))?;
+            let local = match server {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let socket = tokio::net::UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+            socket.connect(server).await.unwrap();
 
             if let (Some(scanner), Some(device)) = (
                 database.data.scanners.get(&device_position.scanner),
@@ -135,6 +162,9 @@ async fn main() -> anyhow::Result<()> {
                     uuid: uuid::Uuid::new_v4(),
                     content: shared::messages::scanner::ScannerContent::Register {
                         mac: scanner.mac.clone(),
+                        protocol: shared::messages::scanner::PROTOCOL_VERSION,
+                        firmware: format!("cli {}", env!("CARGO_PKG_VERSION")),
+                        capabilities: shared::messages::scanner::capability::SCAN,
                     },
                 };
 
@@ -146,6 +176,7 @@ async fn main() -> anyhow::Result<()> {
                         mac: device.mac.clone(),
                         rssi: random(),
                         data: hex::decode(&device_position.msg)?,
+                        ..Default::default()
                     }),
                 };
 
@@ -155,6 +186,79 @@ async fn main() -> anyhow::Result<()> {
                 tracing::debug!("Sending scanner message!!!");
             }
         }
+        Commands::Replay(replay) => {
+            let records = Reader::open(&replay.file)?;
+
+            if let Some(target) = replay.target {
+                // Server would drop every unsigned message without telling
+                if config.base.scanner_security != database::config::ScannerSecurity::Mixed {
+                    anyhow::bail!(
+                        "Replay sends unsigned messages, scannerSecurity {:?} rejects them",
+                        config.base.scanner_security
+                    );
+                }
+                // Every recorded source gets its own socket so scanners stay apart
+                let local = match target {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+                let mut sockets: BTreeMap<SocketAddr, tokio::net::UdpSocket> = BTreeMap::new();
+                let mut previous: Option<Record> = None;
+                for record in records {
+                    let record = record?;
+                    if let Some(previous) = &previous {
+                        tokio::time::sleep(delay(previous, &record, replay.speed)).await;
+                    }
+                    let socket = match sockets.entry(record.addr) {
+                        std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+                        std::collections::btree_map::Entry::Vacant(entry) => {
+                            let socket =
+                                tokio::net::UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+                            tracing::info!(
+                                "Replaying {} from {}",
+                                record.addr,
+                                socket.local_addr()?
+                            );
+                            entry.insert(socket)
+                        }
+                    };
+                    socket
+                        .send_to(&rmp_serde::to_vec(&record.message)?, target)
+                        .await?;
+                    previous = Some(record);
+                }
+            } else {
+                let (scanner_sender, _scanner_receiver) = tokio::sync::mpsc::channel(16);
+                let (scanner_stream, _stream_receiver) = tokio::sync::mpsc::channel(16);
+                let context = context::Context {
+                    global_broadcast: tokio::sync::broadcast::Sender::new(16),
+                    web_broadcast: tokio::sync::broadcast::Sender::new(1024),
+                    scanner_sender,
+                    scanner_stream,
+                    database,
+                    alarms: BTreeMap::new(),
+                };
+                let mut web_receiver = context.web_broadcast.subscribe();
+                let context = std::sync::Arc::new(tokio::sync::RwLock::new(context));
+                let mut scanner = ::server::scanner::Scanner::new(context, Vec::new()).await;
+
+                let mut previous: Option<Record> = None;
+                for record in records {
+                    let record = record?;
+                    if let Some(previous) = &previous {
+                        tokio::time::sleep(delay(previous, &record, replay.speed)).await;
+                    }
+                    scanner.replay(record.clone()).await?;
+                    previous = Some(record);
+
+                    while let Ok(msg) = web_receiver.try_recv() {
+                        if let WebMessage::Activity(..) | WebMessage::Event(..) = msg {
+                            println!("{}", serde_json::to_string(&msg)?);
+                        }
+                    }
+                }
+            }
+        }
         Commands::Test => {
             let msg = WebMessage::Alarm(AlarmInfo {
                 uuid: uuid::Uuid::new_v4(),
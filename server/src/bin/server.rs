@@ -1,18 +1,10 @@
 use ::server::context;
 use ::server::database;
 use ::server::database::{entities::Activities, LoadSave};
-use ::server::message;
-use ::server::scanner;
 use ::server::server;
-use ::server::util;
-use ::server::web;
 
-use clap::{builder::Str, Parser};
-use shared::messages::scanner::ScannerEvent;
-use std::{
-    collections::BTreeMap,
-    net::{SocketAddr, ToSocketAddrs},
-};
+use clap::Parser;
+use std::collections::BTreeMap;
 
 use tracing_subscriber::prelude::*;
 
@@ -49,11 +41,12 @@ async fn main() -> anyhow::Result<()> {
     );
 
     let args = Args::parse();
-    let config = crate::database::config::Server::create(args.config)?;
+    let mut config = crate::database::config::Server::create(args.config)?;
+    config.base.scanner_keys()?;
     let data_path = config.base.data_path.clone();
     let auth_path = config.base.auth_path.clone();
 
-    tracing::info!("{}", serde_json::to_string(&config).unwrap());
+    tracing::info!("{}", serde_json::to_string(&config.redacted()).unwrap());
     let database = crate::database::Database {
         data: crate::database::Data::load(&data_path).unwrap_or_default(),
         auth: crate::database::Auth::load(&auth_path).unwrap_or_default(),
@@ -66,16 +59,18 @@ async fn main() -> anyhow::Result<()> {
     database.auth.save(&auth_path)?;
     database.config.save()?;
 
-    let broadcast = SocketAddr::V4(config.base.port_broadcast);
+    let broadcast = config.base.port_broadcast.clone();
 
     let global_broadcast = tokio::sync::broadcast::Sender::new(config.base.query_size);
     let (scanner_sender, scanner_receiver) = tokio::sync::mpsc::channel(config.base.query_size);
+    let (scanner_stream, stream_receiver) = tokio::sync::mpsc::channel(config.base.query_size);
 
     // Creation of context and control structures
     let context = crate::context::Context {
         global_broadcast: global_broadcast.clone(),
         web_broadcast: tokio::sync::broadcast::Sender::new(config.base.query_size),
         scanner_sender,
+        scanner_stream,
         database,
         alarms: BTreeMap::new(),
     };
@@ -92,8 +87,9 @@ async fn main() -> anyhow::Result<()> {
        let mut sig_quit = signal(SignalKind::quit())?;
        let mut sig_term = signal(SignalKind::terminate())?;
     */
-    let mut server = server::Server::new(context, broadcast, global_sender.clone());
-    let server_future = tokio::task::spawn(async move { server.run(scanner_receiver).await });
+    let mut server = server::Server::new(context, broadcast, global_sender.clone()).await;
+    let server_future =
+        tokio::task::spawn(async move { server.run(scanner_receiver, stream_receiver).await });
 
     // Create signals
     let mut sig_int = signal(SignalKind::interrupt())?;
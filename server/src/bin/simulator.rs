@@ -0,0 +1,476 @@
+use ::server::database::LoadSave;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use shared::messages::scanner::{
+    capability, ScanDevice, ScannerContent, ScannerMessage, ScannerWrapped, PROTOCOL_VERSION,
+};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+use clap::Parser;
+use tracing_subscriber::prelude::*;
+
+// Received power at one meter and path loss exponent of a typical badge indoors
+const TX_POWER: f64 = -59.0;
+const PATH_LOSS: f64 = 2.2;
+// Walls between badge and scanner room
+const WALL_LOSS: f64 = 6.0;
+const NOISE: f64 = 3.0;
+// Weaker advertisements are not heard at all
+const SENSITIVITY: f64 = -100.0;
+
+#[derive(Parser)]
+#[command(
+    name = "simulator",
+    version,
+    about = "Virtual scanners and badges for demos, load tests and positioning bugs"
+)]
+struct Args {
+    #[arg(short, long)]
+    config: Option<String>,
+
+    // Rooms, scanners and devices, data path from config by default
+    #[arg(short, long)]
+    data: Option<String>,
+
+    // Scanner address of the server, first portScanner from config by default
+    #[arg(short, long)]
+    server: Option<SocketAddr>,
+
+    // Number of virtual scanners, all scanners from data by default,
+    // missing ones are created and wait for adoption
+    #[arg(short = 'n', long)]
+    scanners: Option<usize>,
+
+    // Number of badges, enabled devices from data are used first
+    #[arg(short = 'm', long, default_value_t = 5)]
+    badges: usize,
+
+    // Advertisement round in ms
+    #[arg(short, long, default_value_t = 1000)]
+    interval: u64,
+
+    // Walking speed in m/s
+    #[arg(long, default_value_t = 1.2)]
+    speed: f64,
+
+    // Map units of room points per meter
+    #[arg(long, default_value_t = 10.0)]
+    scale: f64,
+
+    // JSON file with list of room UUIDs per badge, badges walk them in loop,
+    // random rooms are visited without it
+    #[arg(short, long)]
+    path: Option<String>,
+
+    // Probability of button press per badge and round
+    #[arg(long, default_value_t = 0.01)]
+    press: f64,
+
+    // Battery drain in percent per hour
+    #[arg(long, default_value_t = 1.0)]
+    drain: f64,
+
+    // Same seed gives the same walk, presses and noise
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+type Position = (f64, f64);
+
+#[derive(Clone, Debug)]
+struct Place {
+    room: uuid::Uuid,
+    location: uuid::Uuid,
+    position: Position,
+}
+
+struct VirtualScanner {
+    name: String,
+    mac: Vec<u8>,
+    place: Option<Place>,
+    socket: UdpSocket,
+}
+
+struct Badge {
+    mac: Vec<u8>,
+    name: String,
+    place: Place,
+    position: Position,
+    target: Place,
+    path: Vec<Place>,
+    step: usize,
+    battery: f64,
+    packet: u8,
+}
+
+fn distance(a: Position, b: Position) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+// Log-distance path loss with wall attenuation and gaussian noise
+fn rssi(distance: f64, walls: bool, noise: f64) -> f64 {
+    let walls = if walls { WALL_LOSS } else { 0.0 };
+    TX_POWER - 10.0 * PATH_LOSS * distance.max(0.5).log10() - walls + noise * NOISE
+}
+
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// Legacy advertisement payload, the flags and service data leave 15 bytes for the name
+const ADVERTISEMENT_SIZE: usize = 31;
+const NAME_SIZE: usize = ADVERTISEMENT_SIZE - 2 - 3 - 11;
+
+// Name and Shelly BLU button service data the server decodes, long names are truncated
+fn advertisement(name: &str, packet: u8, battery: u8, button: u8) -> Vec<u8> {
+    let mut end = name.len().min(NAME_SIZE);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = &name[..end];
+
+    let mut data = vec![name.len() as u8 + 1, 9];
+    data.extend(name.as_bytes());
+    data.extend([2, 1, 6]);
+    data.extend([
+        10, 22, 210, 252, 0x44, 0x00, packet, 0x01, battery, 0x3a, button,
+    ]);
+    data
+}
+
+fn random_mac(rng: &mut StdRng) -> Vec<u8> {
+    // Locally administered unicast address
+    let mut mac: Vec<u8> = (0..6).map(|_| rng.gen()).collect();
+    mac[0] = (mac[0] & 0xfc) | 0x02;
+    mac
+}
+
+fn encode(content: ScannerContent) -> anyhow::Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec(&ScannerMessage {
+        uuid: uuid::Uuid::new_v4(),
+        content,
+    })?)
+}
+
+impl VirtualScanner {
+    async fn register(&self, server: SocketAddr) -> anyhow::Result<()> {
+        let register = encode(ScannerContent::Register {
+            mac: self.mac.clone(),
+            protocol: PROTOCOL_VERSION,
+            firmware: format!("simulator {}", env!("CARGO_PKG_VERSION")),
+            capabilities: capability::LEGACY,
+        })?;
+        self.socket.send_to(&register, server).await?;
+        Ok(())
+    }
+
+    // Behaves like keyless firmware, signed or encrypted commands are not understood
+    async fn process(&self, server: SocketAddr, data: &[u8]) -> anyhow::Result<()> {
+        let msg = ScannerWrapped::from_slice(data)
+            .and_then(|wrapped| wrapped.open(None))
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+
+        match msg.content {
+            ScannerContent::Hello | ScannerContent::Restart => self.register(server).await?,
+            ScannerContent::Ping(payload) => {
+                self.socket
+                    .send_to(&encode(ScannerContent::Pong(payload))?, server)
+                    .await?;
+            }
+            ScannerContent::Set(state) => {
+                tracing::info!(
+                    "{}: buzzer {:?} led {:?} scan {:?}",
+                    self.name,
+                    state.buzzer,
+                    state.led,
+                    state.scan
+                );
+            }
+            content => tracing::debug!("{}: {:?}", self.name, content),
+        }
+        Ok(())
+    }
+}
+
+impl Badge {
+    // Walk towards target, next room is picked on arrival
+    fn walk(&mut self, step: f64, places: &[Place], rng: &mut StdRng) {
+        let remaining = distance(self.position, self.target.position);
+        if self.target.location != self.place.location || remaining <= step {
+            // Another location is another building or floor, badge appears there
+            self.position = self.target.position;
+            if self.target.room != self.place.room {
+                tracing::info!("{} entered room {}", self.name, self.target.room);
+            }
+            self.place = self.target.clone();
+            self.target = if self.path.is_empty() {
+                places[rng.gen_range(0..places.len())].clone()
+            } else {
+                self.step = (self.step + 1) % self.path.len();
+                self.path[self.step].clone()
+            };
+        } else {
+            let ratio = step / remaining;
+            self.position.0 += (self.target.position.0 - self.position.0) * ratio;
+            self.position.1 += (self.target.position.1 - self.position.1) * ratio;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_line_number(true)
+        .with_writer(std::io::stderr);
+    let filter = tracing_subscriber::filter::Targets::new()
+        .with_target(env!("CARGO_PKG_NAME"), tracing::Level::DEBUG)
+        .with_target(env!("CARGO_BIN_NAME"), tracing::Level::DEBUG);
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(filter)
+        .init();
+
+    let args = Args::parse();
+    let config = ::server::database::config::Server::create(args.config.clone())?;
+    let data =
+        ::server::database::Data::load(args.data.as_ref().unwrap_or(&config.base.data_path))?;
+    let server = match args.server {
+        Some(server) => server,
+        None => *config
+            .base
+            .port_scanner
+            .first()
+            .ok_or(anyhow::anyhow!("No scanner address configured"))?,
+    };
+    // Unspecified listener is reached on loopback
+    let server = match server.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), server.port())
+        }
+        _ => server,
+    };
+    let local = match server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    // Rooms are walked between their centers, positions are in meters
+    let places: BTreeMap<uuid::Uuid, Place> = data
+        .rooms
+        .values()
+        .map(|room| {
+            let count = room.points.len().max(1) as f64;
+            let (x, y) = room.points.iter().fold((0.0, 0.0), |(x, y), point| {
+                (x + point.0 as f64, y + point.1 as f64)
+            });
+            (
+                room.uuid,
+                Place {
+                    room: room.uuid,
+                    location: room.location,
+                    position: (x / count / args.scale, y / count / args.scale),
+                },
+            )
+        })
+        .collect();
+    let rooms: Vec<Place> = places.values().cloned().collect();
+    if rooms.is_empty() {
+        anyhow::bail!("No rooms to walk in");
+    }
+
+    let mut scanners = Vec::new();
+    let count = args.scanners.unwrap_or(data.scanners.len());
+    let mut known = data.scanners.values();
+    for index in 0..count {
+        let (name, mac, place) = match known.next() {
+            Some(scanner) => (
+                scanner.name.clone(),
+                scanner.mac.clone(),
+                scanner.room.and_then(|room| places.get(&room).cloned()),
+            ),
+            None => (
+                format!("Simulated scanner {}", index + 1),
+                random_mac(&mut rng),
+                Some(rooms[rng.gen_range(0..rooms.len())].clone()),
+            ),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        tracing::info!(
+            "{} {} listens on {}",
+            name,
+            hex::encode(&mac),
+            socket.local_addr()?
+        );
+        scanners.push(VirtualScanner {
+            name,
+            mac,
+            place,
+            socket,
+        });
+    }
+
+    if scanners.is_empty() {
+        anyhow::bail!("No scanners to simulate");
+    }
+
+    let paths: Vec<Vec<uuid::Uuid>> = match &args.path {
+        Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+    let mut devices = data.devices.values().filter(|device| device.enabled);
+    let mut badges = Vec::new();
+    for index in 0..args.badges {
+        let (mac, name) = match devices.next() {
+            Some(device) => (
+                device.mac.clone(),
+                device
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("Badge {}", index + 1)),
+            ),
+            None => (random_mac(&mut rng), format!("Badge {}", index + 1)),
+        };
+        let path: Vec<Place> = match paths.get(index % paths.len().max(1)) {
+            Some(path) => path
+                .iter()
+                .filter_map(|room| places.get(room).cloned())
+                .collect(),
+            None => Vec::new(),
+        };
+        let place = path
+            .first()
+            .cloned()
+            .unwrap_or_else(|| rooms[rng.gen_range(0..rooms.len())].clone());
+        badges.push(Badge {
+            mac,
+            name,
+            position: place.position,
+            target: place.clone(),
+            place,
+            path,
+            step: 0,
+            battery: rng.gen_range(60.0..100.0),
+            packet: 0,
+        });
+    }
+
+    for scanner in &scanners {
+        scanner.register(server).await?;
+    }
+
+    let interval = Duration::from_millis(args.interval);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+            received = futures::future::select_all(scanners.iter().map(|scanner| {
+                Box::pin(async move {
+                    let mut buf = [0u8; 2048];
+                    let (len, _) = scanner.socket.recv_from(&mut buf).await?;
+                    Ok::<_, std::io::Error>((scanner, buf[..len].to_vec()))
+                })
+            })) => {
+                if let (Ok((scanner, data)), _, _) = received {
+                    if let Err(err) = scanner.process(server, &data).await {
+                        tracing::warn!("{}: {}", scanner.name, err);
+                    }
+                }
+                continue;
+            }
+        }
+
+        let seconds = interval.as_secs_f64();
+        for badge in badges.iter_mut() {
+            badge.walk(args.speed * seconds, &rooms, &mut rng);
+            badge.battery = (badge.battery - args.drain * seconds / 3600.0).max(0.0);
+
+            let button = if rng.gen_bool(args.press.clamp(0.0, 1.0)) {
+                let button = [1, 2, 3, 4, 254][rng.gen_range(0..5)];
+                tracing::info!("{} pressed button {}", badge.name, button);
+                button
+            } else {
+                0
+            };
+            badge.packet = badge.packet.wrapping_add(1);
+            let data = advertisement(
+                &badge.name,
+                badge.packet,
+                badge.battery.round() as u8,
+                button,
+            );
+
+            for scanner in &scanners {
+                let Some(place) = scanner
+                    .place
+                    .as_ref()
+                    .filter(|place| place.location == badge.place.location)
+                else {
+                    continue;
+                };
+                let level = rssi(
+                    distance(badge.position, place.position),
+                    place.room != badge.place.room,
+                    gaussian(&mut rng),
+                );
+                if level < SENSITIVITY {
+                    continue;
+                }
+
+                let result = encode(ScannerContent::ScanResult(ScanDevice {
+                    mac: badge.mac.clone(),
+                    rssi: level.round() as i32,
+                    data: data.clone(),
+                    ..Default::default()
+                }))?;
+                if let Err(err) = scanner.socket.send_to(&result, server).await {
+                    tracing::warn!("{}: {}", scanner.name, err);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rssi_falloff() {
+        let near = rssi(1.0, false, 0.0);
+        assert_eq!(near, TX_POWER);
+        assert!(rssi(10.0, false, 0.0) < near - 20.0);
+        assert!(rssi(10.0, true, 0.0) < rssi(10.0, false, 0.0));
+        // Standing at the scanner does not go above the one meter level much
+        assert!(rssi(0.0, false, 0.0) < near + 10.0);
+    }
+
+    #[test]
+    fn badge_advertisement() {
+        let data = advertisement("B1", 7, 80, 2);
+        assert_eq!(
+            data,
+            vec![3, 9, b'B', b'1', 2, 1, 6, 10, 22, 210, 252, 0x44, 0x00, 7, 0x01, 80, 0x3a, 2]
+        );
+
+        let data = advertisement(&"Ä".repeat(300), 7, 80, 2);
+        assert_eq!(data.len(), ADVERTISEMENT_SIZE - 1);
+        assert_eq!(&data[..2], &[15, 9]);
+        assert_eq!(&data[2..16], "Ä".repeat(7).as_bytes());
+    }
+}
@@ -9,10 +9,25 @@ pub struct Context {
     pub global_broadcast: tokio::sync::broadcast::Sender<GlobalMessage>,
     pub web_broadcast: tokio::sync::broadcast::Sender<WebMessage>,
     pub scanner_sender: tokio::sync::mpsc::Sender<ScannerEvent>,
+    // Scanners connected over WebSocket
+    pub scanner_stream: tokio::sync::mpsc::Sender<crate::scanner::stream::StreamEvent>,
     pub database: crate::database::Database,
     pub alarms: BTreeMap<uuid::Uuid, crate::message::web::AlarmInfo>,
 }
 impl Context {
+    // Scanner configuration with outputs of the active alarms
+    pub fn scanner_state(
+        &self,
+        scanner: &database::entities::Scanner,
+    ) -> shared::messages::scanner::State {
+        crate::alarm::state(
+            &self.database.data,
+            &self.alarms,
+            scanner,
+            &self.database.config.base.scanner_radio,
+        )
+    }
+
     /*
     pub fn scanner_set(&mut self, uuid: uuid::Uuid, socket: SocketAddr, mac: Vec<u8>) {
         let now = chrono::offset::Utc::now();
@@ -1,13 +1,13 @@
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr},
 };
 
 use crate::database::entities::{Contact, ContactKind};
 
 use super::LoadSave;
 use mail_send::{mail_builder::MessageBuilder, Credentials, SmtpClientBuilder};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sha2::Digest;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -96,6 +96,18 @@ impl SMS {
     }
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ScannerSecurity {
+    // Accept plain and signed packets, sign only for scanners with key
+    #[default]
+    Mixed,
+    // Accept only signed packets
+    Hashed,
+    // Accept only signed and encrypted packets
+    Encrypted,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", default)]
 pub struct Base {
@@ -107,10 +119,84 @@ pub struct Base {
     pub query_size: usize,
     pub activity_diff: i64,
     pub routine: i64,
-    pub port_web: SocketAddrV4,
-    pub port_scanner: SocketAddrV4,
-    pub port_broadcast: SocketAddrV4,
+    // Listeners bind every address, IPv4 or IPv6, single address is accepted too
+    #[serde(deserialize_with = "addresses")]
+    pub port_web: Vec<SocketAddr>,
+    #[serde(deserialize_with = "addresses")]
+    pub port_scanner: Vec<SocketAddr>,
+    // Broadcast or multicast groups like [ff02::1]:3031 scanners listen on,
+    // scope id like [ff02::1%2]:3031 selects the interface multicast leaves through
+    #[serde(deserialize_with = "addresses")]
+    pub port_broadcast: Vec<SocketAddr>,
+    // Directed broadcasts or multicast groups of routed subnets, Hello is sent to each of them
+    #[serde(deserialize_with = "addresses")]
+    pub discovery_broadcasts: Vec<SocketAddr>,
+    // Hop limit of IPv6 multicast, 1 keeps it on the local link
+    pub scanner_multicast_hops: u32,
+    pub scanner_security: ScannerSecurity,
+    // First retransmission of unconfirmed command in ms, doubled with every attempt
+    pub scanner_ack_timeout: u64,
+    pub scanner_ack_retries: u32,
+    // How long scanner blinks on identify request, in seconds
+    pub scanner_identify: u32,
+    // Token bucket per source address, packets per second and burst
+    pub scanner_rate: u32,
+    pub scanner_burst: u32,
+    // Token bucket shared by all sources which are not registered scanners
+    pub scanner_unknown_rate: u32,
+    pub scanner_unknown_burst: u32,
+    // Scanners with older protocol are refused
+    pub scanner_protocol: u32,
+    // Scanners waiting for adoption, the least recently seen one makes room for a new one
+    pub scanner_pending_limit: usize,
+    // Scanner without any traffic for this many seconds is offline
+    pub scanner_timeout: i64,
+    // Hex encoded X25519 keypair for encrypted scanner channel
+    pub scanner_secret: String,
+    pub scanner_public: String,
+    // Scanners presenting it in /api/scanner?token= may send unsigned frames over WebSocket,
+    // empty accepts only signed frames and Register of new scanners there
+    pub scanner_stream_token: String,
+    // Site-wide radio parameters, scanners override them one by one
+    pub scanner_radio: shared::messages::scanner::Radio,
+    // Hex encoded mac prefixes and 16-bit service UUIDs scanners forward besides enabled devices
+    pub filter_prefixes: Vec<String>,
+    pub filter_services: Vec<u16>,
+    // Scanners forward every n-th other advertisement for discovery, 0 drops them
+    pub filter_sample: u32,
+    // Advertisement decoders by device type, devices of other types use the default ones
+    pub decoders: std::collections::BTreeMap<String, Vec<String>>,
+    pub default_decoders: Vec<String>,
+    // Append-only file every received scanner message is recorded to, empty disables capture
+    pub scanner_capture: String,
+    // Capture reaching this many bytes is renamed to <file>.1 and started again, 0 never rotates
+    pub scanner_capture_limit: u64,
+    // Directory with <version>.bin images, empty means firmware next to frontend directory,
+    // frontend/firmware when frontend_path is empty too
+    pub firmware_path: String,
+    // Base URL scanners download images from, empty means derived from port_web
+    pub firmware_url: String,
+    // Update without progress for this many seconds failed
+    pub firmware_timeout: u64,
+}
+// Older configs hold one address per listener
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Addresses {
+    One(SocketAddr),
+    Many(Vec<SocketAddr>),
 }
+
+fn addresses<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Addresses::deserialize(deserializer)? {
+        Addresses::One(addr) => vec![addr],
+        Addresses::Many(addrs) => addrs,
+    })
+}
+
 impl Default for Base {
     fn default() -> Self {
         Base {
@@ -120,22 +206,108 @@ impl Default for Base {
             frontend_path: String::new(),
             salt: String::new(),
             query_size: 16,
-            port_web: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3030),
-            port_scanner: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3031),
-            port_broadcast: SocketAddrV4::new(Ipv4Addr::BROADCAST, 3031),
+            port_web: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3030))],
+            port_scanner: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, 3031))],
+            port_broadcast: vec![SocketAddr::from((Ipv4Addr::BROADCAST, 3031))],
+            discovery_broadcasts: Vec::new(),
+            scanner_multicast_hops: 1,
             activity_diff: 15,
             routine: 5,
+            scanner_security: ScannerSecurity::Mixed,
+            scanner_ack_timeout: 500,
+            scanner_ack_retries: 5,
+            scanner_timeout: 30,
+            scanner_identify: 10,
+            scanner_protocol: 0,
+            scanner_pending_limit: 64,
+            scanner_rate: 50,
+            scanner_burst: 100,
+            scanner_unknown_rate: 20,
+            scanner_unknown_burst: 50,
+            scanner_secret: String::new(),
+            scanner_stream_token: String::new(),
+            scanner_public: String::new(),
+            scanner_radio: shared::messages::scanner::Radio {
+                interval: Some(100),
+                window: Some(80),
+                active: Some(true),
+                filter_duplicates: Some(true),
+                min_rssi: None,
+            },
+            filter_prefixes: Vec::new(),
+            filter_services: Vec::new(),
+            filter_sample: 20,
+            decoders: std::collections::BTreeMap::new(),
+            default_decoders: vec![String::from("name"), String::from("bthome")],
+            scanner_capture: String::new(),
+            scanner_capture_limit: 100 * 1024 * 1024,
+            firmware_path: String::new(),
+            firmware_url: String::new(),
+            firmware_timeout: 600,
         }
     }
 }
 
 impl Base {
+    pub fn decoders(&self, device_type: &str) -> &[String] {
+        self.decoders
+            .get(device_type)
+            .unwrap_or(&self.default_decoders)
+    }
+
     pub fn get_hashed(&self, data: &str) -> String {
         let mut hasher = sha2::Sha256::new();
         hasher.update(&self.salt);
         hasher.update(data);
         hex::encode(hasher.finalize())
     }
+
+    pub fn firmware_dir(&self) -> std::path::PathBuf {
+        if !self.firmware_path.is_empty() {
+            std::path::PathBuf::from(&self.firmware_path)
+        } else if self.frontend_path.is_empty() {
+            std::path::PathBuf::from("frontend/firmware")
+        } else {
+            std::path::Path::new(&self.frontend_path)
+                .parent()
+                .unwrap_or(std::path::Path::new("."))
+                .join("firmware")
+        }
+    }
+
+    // Highest accepted scanner sequences are saved next to data, apart from it
+    pub fn sequence_path(&self) -> String {
+        std::path::Path::new(&self.data_path)
+            .parent()
+            .unwrap_or(std::path::Path::new("."))
+            .join("sequences.json")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Scanners need routable address, unspecified port_web is not usable
+    pub fn firmware_url(&self) -> Option<String> {
+        if !self.firmware_url.is_empty() {
+            Some(self.firmware_url.trim_end_matches('/').to_string())
+        } else {
+            self.port_web
+                .iter()
+                .find(|addr| !addr.ip().is_unspecified())
+                .map(|addr| format!("http://{}/firmware", addr))
+        }
+    }
+
+    // Generate missing server keypair for encrypted scanner channel
+    pub fn scanner_keys(&mut self) -> anyhow::Result<()> {
+        if self.scanner_secret.is_empty() {
+            self.scanner_secret = hex::encode(shared::messages::wrapped::secret_key());
+        }
+
+        self.scanner_public = hex::encode(shared::messages::wrapped::public_key(&hex::decode(
+            &self.scanner_secret,
+        )?)?);
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
@@ -144,11 +316,20 @@ pub struct Setting {
     pub test: u64,
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScannerOffline {
+    pub notification: uuid::Uuid,
+    pub group: uuid::Uuid,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", default)]
 pub struct Notification {
     pub email: Email,
     pub sms: SMS,
+    // Notify contact group when scanner goes offline
+    pub scanner_offline: Option<ScannerOffline>,
 }
 
 impl Notification {
@@ -197,12 +378,51 @@ impl Notification {
     }
 }
 
+// Bridge to building management, topics are under prefix
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Mqtt {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub prefix: String,
+    // Accept scanner traffic on <prefix>/scanner/<mac>/scan
+    pub scanners: bool,
+    // Scanners accepted over MQTT at once, silent ones are dropped after scanner_idle seconds
+    pub scanner_limit: usize,
+    pub scanner_idle: u64,
+    // Keep alive interval in seconds
+    pub keep_alive: u64,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::from("127.0.0.1"),
+            port: 1883,
+            client_id: String::from("evac-server"),
+            username: String::new(),
+            password: String::new(),
+            prefix: String::from("evac"),
+            scanners: false,
+            scanner_limit: 64,
+            scanner_idle: 120,
+            keep_alive: 30,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", default)]
 pub struct Server {
     pub base: Base,
     pub notification: Notification,
     pub setting: Setting,
+    pub mqtt: Mqtt,
 }
 impl LoadSave for Server {}
 
@@ -211,6 +431,23 @@ impl Server {
         LoadSave::save(self, &self.base.config_path)
     }
 
+    // Copy for logs with secrets and passwords removed
+    pub fn redacted(&self) -> Server {
+        let mut config = self.clone();
+        let hidden = |value: &mut String| {
+            if !value.is_empty() {
+                *value = String::from("***");
+            }
+        };
+        hidden(&mut config.base.salt);
+        hidden(&mut config.base.scanner_secret);
+        hidden(&mut config.base.scanner_stream_token);
+        hidden(&mut config.notification.email.password);
+        hidden(&mut config.notification.sms.auth);
+        hidden(&mut config.mqtt.password);
+        config
+    }
+
     pub fn create(config_path: Option<String>) -> anyhow::Result<Server> {
         let config_path = config_path.unwrap_or(
             std::env::var("EVAC_SERVER_CONFIG").unwrap_or(String::from("../data/server.json")),
@@ -227,3 +464,71 @@ impl Server {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_addresses() {
+        // Config written before listeners took several addresses
+        let base: Base = serde_json::from_value(serde_json::json!({
+            "portWeb": "0.0.0.0:3030",
+            "portScanner": "192.168.1.10:3031",
+            "portBroadcast": "255.255.255.255:3031",
+            "discoveryBroadcasts": ["10.0.1.255:3031"],
+        }))
+        .unwrap();
+        assert_eq!(base.port_web, vec!["0.0.0.0:3030".parse().unwrap()]);
+        assert_eq!(
+            base.port_scanner,
+            vec!["192.168.1.10:3031".parse().unwrap()]
+        );
+        assert_eq!(base.firmware_url(), None);
+
+        let base: Base = serde_json::from_value(serde_json::json!({
+            "portWeb": ["0.0.0.0:3030", "[2001:db8::1]:3030"],
+            "portBroadcast": ["[ff02::1]:3031"],
+        }))
+        .unwrap();
+        assert_eq!(base.port_web.len(), 2);
+        assert_eq!(base.port_broadcast, vec!["[ff02::1]:3031".parse().unwrap()]);
+        assert_eq!(base.port_scanner, Base::default().port_scanner);
+        assert_eq!(
+            base.firmware_url().as_deref(),
+            Some("http://[2001:db8::1]:3030/firmware")
+        );
+
+        let base: Base = serde_json::from_str(&serde_json::to_string(&base).unwrap()).unwrap();
+        assert_eq!(base.port_web.len(), 2);
+    }
+
+    #[test]
+    fn redacted_secrets() {
+        let mut config = Server::default();
+        config.base.scanner_keys().unwrap();
+        config.mqtt.password = String::from("mqtt-password");
+
+        let logged = serde_json::to_string(&config.redacted()).unwrap();
+        assert!(!logged.contains(&config.base.scanner_secret));
+        assert!(!logged.contains("mqtt-password"));
+        // Empty values show they are not set
+        assert!(logged.contains(r#""salt":"""#));
+    }
+
+    #[test]
+    fn firmware_dir() {
+        let mut base = Base::default();
+        assert_eq!(
+            base.firmware_dir(),
+            std::path::Path::new("frontend/firmware")
+        );
+        base.frontend_path = String::from("./frontend/dist");
+        assert_eq!(
+            base.firmware_dir(),
+            std::path::Path::new("./frontend/firmware")
+        );
+        base.firmware_path = String::from("/srv/firmware");
+        assert_eq!(base.firmware_dir(), std::path::Path::new("/srv/firmware"));
+    }
+}
@@ -2,7 +2,7 @@ use std::{collections::BTreeMap, net::Ipv4Addr, os::unix::fs::chroot};
 
 use chrono::prelude::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use shared::messages::scanner;
+use shared::messages::{pattern, scanner};
 use uuid::Uuid;
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -18,6 +18,185 @@ pub struct Scanner {
     pub led: bool,
     pub buzzer: bool,
     pub scan: bool,
+    // Hex encoded pre-shared key for signed scanner messages
+    pub key: Option<String>,
+    // Hex encoded X25519 public key for encrypted scanner messages
+    pub public_key: Option<String>,
+    pub online: bool,
+    // Last Ping/Pong round trip in ms
+    pub rtt: Option<u64>,
+    // Scanner clock minus server clock in ms, estimated from Ping/Pong
+    pub clock_offset: Option<i64>,
+    // Negotiated protocol version, firmware and capabilities from Register
+    pub protocol: u32,
+    pub firmware: String,
+    pub capabilities: u32,
+    // Latest health report and the few before it, oldest first
+    pub status: Option<ScannerStatus>,
+    pub status_history: Vec<ScannerStatus>,
+    pub diagnostics: ScannerDiagnostics,
+    // Radio parameters of this scanner, unset ones come from site defaults
+    pub radio: scanner::Radio,
+}
+
+// Counters of rejected scanner messages
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScannerDiagnostics {
+    pub duplicate: u64,
+    pub stale: u64,
+    pub unsequenced: u64,
+    pub rate_limited: u64,
+}
+
+pub const STATUS_HISTORY: usize = 20;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScannerStatus {
+    pub timestamp: DateTime<Utc>,
+    pub status: scanner::Status,
+}
+
+// Firmware image available for scanner update
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Firmware {
+    pub version: String,
+    pub file: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+// Scanner which registered but was not adopted by admin yet
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PendingScanner {
+    pub uuid: uuid::Uuid,
+    pub ip: String,
+    pub port: u16,
+    pub mac: Vec<u8>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub protocol: u32,
+    pub firmware: String,
+    pub capabilities: u32,
+    // Rejected scanners are ignored until adopted
+    pub rejected: bool,
+}
+
+impl Scanner {
+    pub fn key(&self) -> Option<Vec<u8>> {
+        self.key.as_ref().and_then(|key| hex::decode(key).ok())
+    }
+
+    // Copy for browsers, the pre-shared key never leaves the server
+    pub fn redacted(&self) -> Scanner {
+        Scanner {
+            key: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn public_key(&self) -> Option<Vec<u8>> {
+        self.public_key
+            .as_ref()
+            .and_then(|key| hex::decode(key).ok())
+    }
+
+    pub fn set_status(&mut self, status: ScannerStatus) {
+        self.firmware = status.status.firmware.clone();
+        if let Some(previous) = self.status.replace(status) {
+            self.status_history.push(previous);
+        }
+        if self.status_history.len() > STATUS_HISTORY {
+            self.status_history.remove(0);
+        }
+    }
+
+    // Full state pushed on register and configuration change
+    pub fn state(&self, defaults: &scanner::Radio) -> scanner::State {
+        scanner::State {
+            scan: Some(self.scan),
+            led: Some(self.led),
+            buzzer: Some(self.buzzer),
+            radio: Some(self.radio.merge(defaults)),
+            ..Default::default()
+        }
+    }
+
+    // Stored address, IPv4 or IPv6 with optional zone
+    pub fn addr(&self) -> Option<std::net::SocketAddr> {
+        crate::util::parse_addr(&self.ip, self.port)
+    }
+
+    pub fn has_capability(&self, capability: u32) -> bool {
+        // Scanners before versioning do not announce anything
+        let capabilities = if self.protocol == 0 {
+            scanner::capability::LEGACY
+        } else {
+            self.capabilities
+        };
+        capabilities & capability == capability
+    }
+
+    // Drop parts of message the scanner does not support, None when nothing is left
+    pub fn adapt(&self, message: &scanner::ScannerMessage) -> Option<scanner::ScannerMessage> {
+        use scanner::capability;
+
+        let content = match &message.content {
+            scanner::ScannerContent::Ping(..)
+            | scanner::ScannerContent::Pong(..)
+            | scanner::ScannerContent::TimedPing(..)
+                if !self.has_capability(capability::PING) =>
+            {
+                return None;
+            }
+            scanner::ScannerContent::Restart if !self.has_capability(capability::RESTART) => {
+                return None;
+            }
+            scanner::ScannerContent::Identify(..) if !self.has_capability(capability::IDENTIFY) => {
+                return None;
+            }
+            scanner::ScannerContent::Update { .. } if !self.has_capability(capability::UPDATE) => {
+                return None;
+            }
+            scanner::ScannerContent::Filter(..) if !self.has_capability(capability::FILTER) => {
+                return None;
+            }
+            scanner::ScannerContent::Set(state) => {
+                let state = scanner::State {
+                    scan: state.scan.filter(|_| self.has_capability(capability::SCAN)),
+                    led: state.led.filter(|_| self.has_capability(capability::LED)),
+                    buzzer: state
+                        .buzzer
+                        .filter(|_| self.has_capability(capability::BUZZER)),
+                    radio: state
+                        .radio
+                        .clone()
+                        .filter(|_| self.has_capability(capability::RADIO)),
+                    buzzer_pattern: state
+                        .buzzer_pattern
+                        .clone()
+                        .filter(|_| self.has_capability(capability::PATTERN)),
+                    led_pattern: state
+                        .led_pattern
+                        .clone()
+                        .filter(|_| self.has_capability(capability::PATTERN)),
+                };
+                if state == scanner::State::default() {
+                    return None;
+                }
+                scanner::ScannerContent::Set(state)
+            }
+            content => content.clone(),
+        };
+
+        Some(scanner::ScannerMessage {
+            content,
+            uuid: message.uuid,
+        })
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -29,6 +208,10 @@ pub struct Device {
     pub enabled: bool,
     pub battery: Option<u8>,
     pub last_activity: chrono::DateTime<chrono::Utc>,
+    // Selects advertisement decoders in config, empty uses the default ones
+    pub device_type: String,
+    // Latest sensor readings from advertisements
+    pub sensors: BTreeMap<SensorKind, i64>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -50,8 +233,6 @@ impl Activities {
         timestamp: chrono::DateTime<chrono::Utc>,
         irssi: i64,
     ) -> bool {
-        let now = chrono::offset::Utc::now();
-
         let best1 = self.best(device_uuid);
 
         // Record exists
@@ -60,18 +241,20 @@ impl Activities {
         }
 
         if let Some(activities) = self.map.get_mut(&device_uuid) {
-            // If scanner exists, replace value with the lastest result
+            // If scanner exists, replace value with the lastest result, delayed older one is dropped
             if let Some(activity) = activities
                 .iter_mut()
                 .find(|a| a.scanner_uuid == scanner_uuid)
             {
-                activity.irssi = irssi;
-                activity.timestamp = timestamp;
+                if timestamp >= activity.timestamp {
+                    activity.irssi = irssi;
+                    activity.timestamp = timestamp;
+                }
             }
             // Create a new record
             else {
                 activities.push(DeviceActivity {
-                    timestamp: now,
+                    timestamp,
                     irssi,
                     scanner_uuid: scanner_uuid,
                 });
@@ -171,6 +354,19 @@ pub struct Event {
     pub kind: EventKind,
 }
 
+// Values are in thousandths of °C, %, lux, V or degrees, motion and window are 0 or 1
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum SensorKind {
+    Temperature,
+    Humidity,
+    Illuminance,
+    Voltage,
+    Motion,
+    Window,
+    Rotation,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub enum EventKind {
@@ -191,8 +387,37 @@ pub struct Alarm {
     pub name: String,
     pub buzzer: bool,
     pub led: bool,
+    // Signalling of buzzer and LED, continuous without pattern
+    pub pattern: Option<uuid::Uuid>,
     pub notification: uuid::Uuid,
     pub group: uuid::Uuid,
+    // Higher priority wins when alarms overlap on a scanner
+    pub priority: u32,
+    // Target scope, without locations and rooms the alarm covers the whole site
+    pub locations: Vec<uuid::Uuid>,
+    pub rooms: Vec<uuid::Uuid>,
+}
+
+impl Alarm {
+    pub fn is_global(&self) -> bool {
+        self.locations.is_empty() && self.rooms.is_empty()
+    }
+
+    pub fn covers(&self, room: &Room) -> bool {
+        self.is_global()
+            || self.rooms.contains(&room.uuid)
+            || self.locations.contains(&room.location)
+    }
+}
+
+// Named buzzer and LED signalling, a drill should not sound like a fire
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Pattern {
+    pub uuid: uuid::Uuid,
+    pub name: String,
+    pub buzzer: pattern::Pattern,
+    pub led: pattern::Pattern,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
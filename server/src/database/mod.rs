@@ -11,6 +11,9 @@ use crate::database::entities::{
 pub mod config;
 pub mod entities;
 
+// Receive buffer of scanners, every part of allow-list must fit it in any envelope
+const SCANNER_BUFFER: usize = 1024;
+
 pub trait LoadSave {
     fn load(path: &str) -> anyhow::Result<Self>
     where
@@ -63,10 +66,12 @@ impl LoadSave for Auth {}
 #[serde(rename_all = "camelCase", default)]
 pub struct Data {
     pub scanners: BTreeMap<uuid::Uuid, entities::Scanner>,
+    pub pending_scanners: BTreeMap<uuid::Uuid, entities::PendingScanner>,
     pub devices: BTreeMap<uuid::Uuid, entities::Device>,
     pub locations: BTreeMap<uuid::Uuid, entities::Location>,
     pub rooms: BTreeMap<uuid::Uuid, entities::Room>,
     pub alarms: BTreeMap<uuid::Uuid, Alarm>,
+    pub patterns: BTreeMap<uuid::Uuid, entities::Pattern>,
     pub notifications: BTreeMap<uuid::Uuid, entities::Notification>,
     pub contacts: BTreeMap<uuid::Uuid, entities::Contact>,
     pub contact_group: BTreeMap<uuid::Uuid, entities::ContactGroup>,
@@ -287,6 +292,7 @@ impl Default for Data {
                 ),
             ]),
             alarms: BTreeMap::new(),
+            patterns: BTreeMap::new(),
             notifications: BTreeMap::new(),
             contacts: BTreeMap::from_iter([
                 (
@@ -337,6 +343,7 @@ impl Default for Data {
                 ),
             ]),
 
+            pending_scanners: BTreeMap::new(),
             backups: HashSet::new(),
         }
     }
@@ -386,6 +393,30 @@ impl Data {
     pub fn is_notification_used(&self, uuid: &uuid::Uuid) -> bool {
         false
     }
+
+    // Unassigned scanners only take site-wide alarms
+    pub fn alarm_covers(&self, alarm: &entities::Alarm, scanner: &entities::Scanner) -> bool {
+        alarm.is_global()
+            || scanner
+                .room
+                .and_then(|room| self.rooms.get(&room))
+                .is_some_and(|room| alarm.covers(room))
+    }
+
+    // Scanners signalling the alarm
+    pub fn alarm_scanners(&self, alarm: &entities::Alarm) -> Vec<uuid::Uuid> {
+        self.scanners
+            .values()
+            .filter(|scanner| self.alarm_covers(alarm, scanner))
+            .map(|scanner| scanner.uuid)
+            .collect()
+    }
+
+    pub fn is_pattern_used(&self, uuid: &uuid::Uuid) -> bool {
+        self.alarms
+            .values()
+            .any(|alarm| alarm.pattern.as_ref() == Some(uuid))
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
@@ -411,4 +442,95 @@ impl Database {
         self.data = Data::load(path)?;
         Ok(())
     }
+
+    // Allow-list pushed to scanners, enabled devices and configured prefixes and services,
+    // long one is split into parts which fit into a datagram
+    pub fn scanner_filters(&self) -> Vec<shared::messages::scanner::Filter> {
+        let base = &self.config.base;
+        let filter = shared::messages::scanner::Filter {
+            macs: self
+                .data
+                .devices
+                .values()
+                .filter(|device| device.enabled)
+                .map(|device| device.mac.clone())
+                .collect(),
+            prefixes: base
+                .filter_prefixes
+                .iter()
+                .filter_map(|prefix| match hex::decode(prefix.replace(':', "")) {
+                    Ok(prefix) => Some(prefix),
+                    Err(err) => {
+                        tracing::warn!("Invalid mac prefix {}: {}", prefix, err);
+                        None
+                    }
+                })
+                .collect(),
+            services: base.filter_services.clone(),
+            sample: base.filter_sample,
+            part: None,
+        };
+        filter.split(rand::random(), |filter| {
+            let message = shared::messages::scanner::ScannerMessage {
+                uuid: uuid::Uuid::from_bytes([0xff; 16]),
+                content: shared::messages::scanner::ScannerContent::Filter(filter.clone()),
+            };
+            rmp_serde::to_vec(&message)
+                .is_ok_and(|data| shared::messages::wrapped::max_size(data.len()) <= SCANNER_BUFFER)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::messages::scanner::{ScannerContent, ScannerMessage, ScannerWrapped};
+
+    #[test]
+    fn scanner_filter_parts() {
+        let mut database = Database::default();
+        database.config.base.filter_prefixes = vec![String::from("3c:e9:0e")];
+        database.config.base.filter_services = vec![0xfcd2, 0x180f];
+        database.data.devices = (0..300u16)
+            .map(|i| {
+                let [high, low] = i.to_be_bytes();
+                let device = entities::Device {
+                    uuid: uuid::Uuid::new_v4(),
+                    mac: vec![0xff, 0xee, 0xdd, 0xcc, 0x80 | high, 0x80 | low],
+                    enabled: i % 10 != 0,
+                    ..Default::default()
+                };
+                (device.uuid, device)
+            })
+            .collect();
+
+        // Worst case envelope, signed with sequence and encrypted
+        let key = [0xffu8; 32];
+        let public_key =
+            shared::messages::wrapped::public_key(&shared::messages::wrapped::secret_key())
+                .unwrap();
+        let filters = database.scanner_filters();
+        assert!(filters.len() > 1);
+        for filter in &filters {
+            let message = ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content: ScannerContent::Filter(filter.clone()),
+            };
+            let data = ScannerWrapped::sequenced(&message, &key, u64::MAX)
+                .unwrap()
+                .encrypt(&public_key)
+                .unwrap()
+                .to_vec()
+                .unwrap();
+            assert!(
+                data.len() <= SCANNER_BUFFER,
+                "Filter part of {} bytes",
+                data.len()
+            );
+        }
+
+        let filter = shared::messages::scanner::Filter::assemble(&filters).unwrap();
+        assert_eq!(filter.macs.len(), 270);
+        assert_eq!(filter.prefixes, vec![vec![0x3c, 0xe9, 0x0e]]);
+    }
 }
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::database::entities::Firmware;
+
+pub const EXTENSION: &str = "bin";
+
+// Images are stored as <version>.bin in the firmware directory
+pub fn list(dir: &Path) -> anyhow::Result<Vec<Firmware>> {
+    let mut firmware = Vec::new();
+    if !dir.is_dir() {
+        return Ok(firmware);
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == EXTENSION) {
+            firmware.push(load(&path)?);
+        }
+    }
+
+    firmware.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(firmware)
+}
+
+pub fn find(dir: &Path, version: &str) -> anyhow::Result<Firmware> {
+    // Version is part of the file name, it must not escape the directory
+    if version.is_empty() || version.starts_with('.') || version.contains(['/', '\\']) {
+        anyhow::bail!("Invalid firmware version: {}", version);
+    }
+
+    load(&dir.join(format!("{}.{}", version, EXTENSION)))
+}
+
+fn load(path: &Path) -> anyhow::Result<Firmware> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Unable to read firmware: {}", path.display()))?;
+
+    Ok(Firmware {
+        version: path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        file: path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        sha256: hex::encode(Sha256::digest(&data)),
+        size: data.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_and_find() {
+        let dir = std::env::temp_dir().join(format!("evac-firmware-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.1.0.bin"), b"new").unwrap();
+        std::fs::write(dir.join("1.0.0.bin"), b"old").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"skip").unwrap();
+
+        let firmware = list(&dir).unwrap();
+        assert_eq!(
+            firmware
+                .iter()
+                .map(|f| f.version.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1.0.0", "1.1.0"]
+        );
+
+        let found = find(&dir, "1.1.0").unwrap();
+        assert_eq!(found.file, "1.1.0.bin");
+        assert_eq!(found.size, 3);
+        assert_eq!(found.sha256, hex::encode(Sha256::digest(b"new")));
+
+        assert!(find(&dir, "../1.0.0").is_err());
+        assert!(find(&dir, "2.0.0").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
+pub mod alarm;
 pub mod context;
 pub mod database;
+pub mod firmware;
 pub mod message;
+pub mod mqtt;
 pub mod scanner;
 pub mod server;
 pub mod util;
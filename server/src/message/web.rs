@@ -26,6 +26,52 @@ pub struct AlarmInfo {
     pub room: String,
 }
 
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScannerUnconfirmed {
+    pub scanner: uuid::Uuid,
+    pub message: uuid::Uuid,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScannerAdopt {
+    pub uuid: uuid::Uuid,
+    pub name: String,
+    pub room: Option<uuid::Uuid>,
+}
+
+// Pre-shared key of a scanner in hex, None removes it
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScannerKey {
+    pub scanner: uuid::Uuid,
+    pub key: Option<String>,
+}
+
+// Update selected scanners and all scanners in selected rooms
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FirmwareUpdate {
+    pub version: String,
+    pub scanners: Vec<uuid::Uuid>,
+    pub rooms: Vec<uuid::Uuid>,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FirmwareProgress {
+    pub scanner: uuid::Uuid,
+    pub version: String,
+    pub state: shared::messages::scanner::UpdateState,
+    pub progress: u8,
+}
+
+// Source without address stands for all unknown sources together
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScannerThrottled {
+    pub source: Option<std::net::SocketAddr>,
+    pub scanner: Option<uuid::Uuid>,
+    pub dropped: u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Auth {
     Token(String),
@@ -86,6 +132,24 @@ pub enum WebMessage {
     ScannerDetail(crate::database::entities::Scanner),
     ScannerRemove(uuid::Uuid),
     ScannerRemoved(uuid::Uuid),
+    ScannerUnconfirmed(ScannerUnconfirmed),
+    ScannerOnline(uuid::Uuid),
+    ScannerOffline(uuid::Uuid),
+    ScannerRestart(uuid::Uuid),
+    ScannerIdentify(uuid::Uuid),
+    // Write-only, scanners are sent to browsers without their keys
+    ScannerSetKey(ScannerKey),
+
+    PendingScannerList(Vec<crate::database::entities::PendingScanner>),
+    PendingScannerDetail(crate::database::entities::PendingScanner),
+    PendingScannerRemoved(uuid::Uuid),
+    ScannerAdopt(ScannerAdopt),
+    ScannerReject(uuid::Uuid),
+    ScannerThrottled(ScannerThrottled),
+
+    FirmwareList(Vec<crate::database::entities::Firmware>),
+    FirmwareUpdate(FirmwareUpdate),
+    FirmwareProgress(FirmwareProgress),
 
     DeviceList(Vec<crate::database::entities::Device>),
     DeviceSet(crate::database::entities::Device),
@@ -109,6 +173,12 @@ pub enum WebMessage {
     Alarm(AlarmInfo),
     AlarmStop(uuid::Uuid),
 
+    PatternList(Vec<crate::database::entities::Pattern>),
+    PatternSet(crate::database::entities::Pattern),
+    PatternDetail(crate::database::entities::Pattern),
+    PatternRemove(uuid::Uuid),
+    PatternRemoved(uuid::Uuid),
+
     Notify {
         uuid: uuid::Uuid,
         group: uuid::Uuid,
@@ -147,3 +217,19 @@ pub enum WebMessage {
     Backup(String),
     Restore(String),
 }
+
+impl WebMessage {
+    // Copy without secrets for browsers, None when the message has none
+    pub fn redacted(&self) -> Option<WebMessage> {
+        match self {
+            WebMessage::ScannerDetail(scanner) => {
+                Some(WebMessage::ScannerDetail(scanner.redacted()))
+            }
+            WebMessage::ScannerSet(scanner) => Some(WebMessage::ScannerSet(scanner.redacted())),
+            WebMessage::ScannerList(scanners) => Some(WebMessage::ScannerList(
+                scanners.iter().map(|s| s.redacted()).collect(),
+            )),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use shared::messages::global::GlobalMessage;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    context::ContextWrapped,
+    database::{config, Database},
+    message::web::WebMessage,
+    scanner::stream::StreamEvent,
+};
+
+// Scanners on MQTT have no socket, they get a unique local address derived from mac
+pub fn scanner_addr(mac: &[u8]) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    octets[..2].copy_from_slice(&[0xfd, 0x00]);
+    let start = 16 - mac.len().min(8);
+    octets[start..].copy_from_slice(&mac[mac.len() - (16 - start)..]);
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), 1883)
+}
+
+// Mac of scanner publishing to <prefix>/scanner/<mac>/scan
+pub fn scan_topic(prefix: &str, topic: &str) -> Option<Vec<u8>> {
+    let mac = topic
+        .strip_prefix(prefix)?
+        .strip_prefix("/scanner/")?
+        .strip_suffix("/scan")?;
+    hex::decode(mac.replace(':', "")).ok()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    pub topic: String,
+    pub retain: bool,
+    // Empty retained payload clears the state
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct Presence {
+    device: uuid::Uuid,
+    name: Option<String>,
+    scanner: uuid::Uuid,
+    room: Option<uuid::Uuid>,
+    location: Option<uuid::Uuid>,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Turns web messages into MQTT state, presence is published only when device changes room
+pub struct Publisher {
+    prefix: String,
+    rooms: BTreeMap<uuid::Uuid, Option<uuid::Uuid>>,
+}
+
+impl Publisher {
+    pub fn new(prefix: String) -> Self {
+        Self {
+            prefix,
+            rooms: BTreeMap::new(),
+        }
+    }
+
+    fn json(&self, topic: String, retain: bool, value: &impl Serialize) -> Option<Publication> {
+        Some(Publication {
+            topic: format!("{}/{}", self.prefix, topic),
+            retain,
+            payload: serde_json::to_vec(value).ok()?,
+        })
+    }
+
+    fn clear(&self, topic: String) -> Option<Publication> {
+        Some(Publication {
+            topic: format!("{}/{}", self.prefix, topic),
+            retain: true,
+            payload: Vec::new(),
+        })
+    }
+
+    pub fn publication(&mut self, msg: &WebMessage, database: &Database) -> Option<Publication> {
+        match msg {
+            WebMessage::Activity(activity) => {
+                // Strongest scanner decides the room
+                let best = database.activities.best(activity.device)?;
+                let scanner = database.data.scanners.get(&best.scanner_uuid)?;
+                let room = scanner.room.and_then(|uuid| database.data.rooms.get(&uuid));
+                let room_uuid = room.map(|room| room.uuid);
+                if self.rooms.insert(activity.device, room_uuid) == Some(room_uuid) {
+                    return None;
+                }
+
+                let presence = Presence {
+                    device: activity.device,
+                    name: database
+                        .data
+                        .devices
+                        .get(&activity.device)
+                        .and_then(|device| device.name.clone()),
+                    scanner: scanner.uuid,
+                    room: room_uuid,
+                    location: room.map(|room| room.location),
+                    timestamp: activity.timestamp,
+                };
+                self.json(
+                    format!("device/{}/presence", activity.device),
+                    true,
+                    &presence,
+                )
+            }
+            WebMessage::DeviceRemoved(uuid) => {
+                self.rooms.remove(uuid);
+                self.clear(format!("device/{}/presence", uuid))
+            }
+            WebMessage::Alarm(info) => self.json(format!("alarm/{}", info.uuid), true, info),
+            WebMessage::AlarmStop(uuid) => self.clear(format!("alarm/{}", uuid)),
+            WebMessage::Event(event) => self.json(String::from("event"), false, event),
+            _ => None,
+        }
+    }
+}
+
+// Scanners seen on MQTT with the time of their last message
+#[derive(Default)]
+struct Connections {
+    inner: BTreeMap<Vec<u8>, (SocketAddr, Instant)>,
+}
+
+impl Connections {
+    // Address of the scanner and whether it is new, none when no more scanners are accepted
+    fn seen(&mut self, mac: &[u8], limit: usize, now: Instant) -> Option<(SocketAddr, bool)> {
+        if let Some((addr, last)) = self.inner.get_mut(mac) {
+            *last = now;
+            return Some((*addr, false));
+        }
+        if self.inner.len() >= limit {
+            return None;
+        }
+
+        let addr = scanner_addr(mac);
+        self.inner.insert(mac.to_vec(), (addr, now));
+        Some((addr, true))
+    }
+
+    // Scanners silent for longer than idle are forgotten, their streams must be closed
+    fn expire(&mut self, idle: Duration, now: Instant) -> Vec<SocketAddr> {
+        let mut expired = Vec::new();
+        self.inner.retain(|_, (addr, last)| {
+            let active = now.saturating_duration_since(*last) <= idle;
+            if !active {
+                expired.push(*addr);
+            }
+            active
+        });
+        expired
+    }
+
+    fn drain(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.inner)
+            .into_values()
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+}
+
+pub struct Bridge {
+    context: ContextWrapped,
+    // Commands to scanners are published by their own task
+    scanners: Connections,
+}
+
+impl Bridge {
+    pub fn new(context: ContextWrapped) -> Self {
+        Self {
+            context,
+            scanners: Connections::default(),
+        }
+    }
+
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let (config, query_size, stream, mut web_receiver, mut global_receiver) = {
+            let context = self.context.read().await;
+            (
+                context.database.config.mqtt.clone(),
+                context.database.config.base.query_size,
+                context.scanner_stream.clone(),
+                context.web_broadcast.subscribe(),
+                context.global_broadcast.subscribe(),
+            )
+        };
+
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive.max(5)));
+        if !config.username.is_empty() {
+            options.set_credentials(&config.username, &config.password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, query_size);
+        let mut publisher = Publisher::new(config.prefix.clone());
+        let idle = Duration::from_secs(config.scanner_idle.max(1));
+        let mut expiry = tokio::time::interval(idle.min(Duration::from_secs(5)));
+
+        loop {
+            tokio::select! {
+                event = eventloop.poll() => {
+                    match event {
+                        // Subscription is renewed with every connection
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            tracing::info!("MQTT connected to {}:{}", config.host, config.port);
+                            if config.scanners {
+                                let topic = format!("{}/scanner/+/scan", config.prefix);
+                                if let Err(err) = client.try_subscribe(topic, QoS::AtMostOnce) {
+                                    tracing::error!("Unable to subscribe: {}", err);
+                                }
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            if let Some(mac) = scan_topic(&config.prefix, &publish.topic) {
+                                if let Some(addr) = self.open(&client, &config, &stream, mac, query_size).await? {
+                                    stream
+                                        .send(StreamEvent::Frame { addr, data: publish.payload.to_vec() })
+                                        .await?;
+                                }
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            tracing::error!("MQTT connection failed: {}", err);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+
+                msg = web_receiver.recv() => {
+                    match msg {
+                        Ok(msg) => {
+                            let publication = {
+                                let context = self.context.read().await;
+                                publisher.publication(&msg, &context.database)
+                            };
+                            if let Some(publication) = publication {
+                                if let Err(err) = client.try_publish(
+                                    publication.topic,
+                                    QoS::AtLeastOnce,
+                                    publication.retain,
+                                    publication.payload,
+                                ) {
+                                    tracing::error!("Unable to publish: {}", err);
+                                }
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(count)) => {
+                            tracing::warn!("MQTT bridge skipped {} messages", count);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+
+                _ = expiry.tick() => {
+                    for addr in self.scanners.expire(idle, Instant::now()) {
+                        tracing::info!("Scanner {} on MQTT is idle", addr);
+                        stream.send(StreamEvent::Close { addr }).await?;
+                    }
+                }
+
+                // Server starts a new bridge with the reloaded config
+                msg = global_receiver.recv() => {
+                    match msg {
+                        Ok(GlobalMessage::Reload) => {
+                            tracing::info!("Reloading MQTT bridge");
+                        }
+                        _ => {
+                            tracing::info!("Stopping MQTT bridge");
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+
+        for addr in self.scanners.drain() {
+            stream.send(StreamEvent::Close { addr }).await?;
+        }
+        let _ = client.try_disconnect();
+
+        Ok(())
+    }
+
+    // First message of a scanner opens its stream, commands go to <prefix>/scanner/<mac>/command
+    async fn open(
+        &mut self,
+        client: &AsyncClient,
+        config: &config::Mqtt,
+        stream: &mpsc::Sender<StreamEvent>,
+        mac: Vec<u8>,
+        query_size: usize,
+    ) -> anyhow::Result<Option<SocketAddr>> {
+        let addr = match self
+            .scanners
+            .seen(&mac, config.scanner_limit, Instant::now())
+        {
+            Some((addr, false)) => return Ok(Some(addr)),
+            Some((addr, true)) => addr,
+            None => {
+                tracing::debug!("MQTT scanner limit reached, {} dropped", hex::encode(&mac));
+                return Ok(None);
+            }
+        };
+
+        let topic = format!("{}/scanner/{}/command", config.prefix, hex::encode(&mac));
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(query_size);
+        let client = client.clone();
+        tokio::spawn(async move {
+            while let Some(data) = receiver.recv().await {
+                if let Err(err) = client.publish(&topic, QoS::AtLeastOnce, false, data).await {
+                    tracing::error!("Unable to publish to {}: {}", topic, err);
+                }
+            }
+        });
+
+        tracing::info!("Scanner {} connected over MQTT", hex::encode(&mac));
+        // Broker authenticates MQTT scanners
+        stream
+            .send(StreamEvent::Open {
+                addr,
+                sender,
+                trusted: true,
+            })
+            .await?;
+        Ok(Some(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::entities::{Device, Room, Scanner},
+        message::web::{Activity, AlarmInfo},
+    };
+
+    #[test]
+    fn topics_and_addresses() {
+        assert_eq!(
+            scan_topic("evac", "evac/scanner/a1b2c3d4e5f6/scan"),
+            Some(vec![0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6])
+        );
+        assert_eq!(
+            scan_topic("evac", "evac/scanner/a1:b2:c3:d4:e5:f6/scan"),
+            Some(vec![0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6])
+        );
+        assert_eq!(scan_topic("evac", "evac/scanner/a1b2/command"), None);
+        assert_eq!(scan_topic("evac", "other/scanner/a1b2/scan"), None);
+
+        assert_eq!(
+            scanner_addr(&[0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6]),
+            "[fd00::a1b2:c3d4:e5f6]:1883".parse().unwrap()
+        );
+        assert_ne!(scanner_addr(&[1, 2, 3]), scanner_addr(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn scanner_connections() {
+        let mut connections = Connections::default();
+        let now = Instant::now();
+
+        // Limit applies to new scanners only
+        assert_eq!(
+            connections.seen(&[1], 2, now),
+            Some((scanner_addr(&[1]), true))
+        );
+        assert_eq!(
+            connections.seen(&[2], 2, now),
+            Some((scanner_addr(&[2]), true))
+        );
+        assert_eq!(connections.seen(&[3], 2, now), None);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            connections.seen(&[1], 2, later),
+            Some((scanner_addr(&[1]), false))
+        );
+
+        // Silent scanner makes room for another one
+        assert_eq!(
+            connections.expire(Duration::from_secs(30), later),
+            vec![scanner_addr(&[2])]
+        );
+        assert_eq!(
+            connections.seen(&[3], 2, later),
+            Some((scanner_addr(&[3]), true))
+        );
+        assert_eq!(connections.drain().len(), 2);
+    }
+
+    #[test]
+    fn presence_and_alarms() {
+        let mut database = Database::default();
+        let room = Room {
+            uuid: uuid::Uuid::new_v4(),
+            location: uuid::Uuid::new_v4(),
+            ..Default::default()
+        };
+        let scanners: Vec<Scanner> = [Some(room.uuid), Some(room.uuid), None]
+            .into_iter()
+            .map(|room| Scanner {
+                uuid: uuid::Uuid::new_v4(),
+                room,
+                ..Default::default()
+            })
+            .collect();
+        let device = Device {
+            uuid: uuid::Uuid::new_v4(),
+            name: Some(String::from("Badge")),
+            enabled: true,
+            ..Default::default()
+        };
+        database.data.rooms.insert(room.uuid, room.clone());
+        for scanner in &scanners {
+            database.data.scanners.insert(scanner.uuid, scanner.clone());
+        }
+        database.data.devices.insert(device.uuid, device.clone());
+
+        let mut publisher = Publisher::new(String::from("evac"));
+        let mut seen = |database: &mut Database, scanner: &Scanner, rssi: i64| {
+            let now = chrono::offset::Utc::now();
+            database.activities.map.clear();
+            database
+                .activities
+                .push(device.uuid, scanner.uuid, now, rssi);
+            publisher.publication(
+                &WebMessage::Activity(Activity {
+                    device: device.uuid,
+                    scanner: scanner.uuid,
+                    rssi,
+                    timestamp: now,
+                }),
+                database,
+            )
+        };
+
+        let topic = format!("evac/device/{}/presence", device.uuid);
+        let first = seen(&mut database, &scanners[0], -50).unwrap();
+        assert_eq!(first.topic, topic);
+        assert!(first.retain);
+        let presence: serde_json::Value = serde_json::from_slice(&first.payload).unwrap();
+        assert_eq!(presence["room"], serde_json::json!(room.uuid));
+        assert_eq!(presence["location"], serde_json::json!(room.location));
+        assert_eq!(presence["name"], "Badge");
+
+        // Another scanner in the same room is not a change
+        assert_eq!(seen(&mut database, &scanners[1], -40), None);
+        let outside = seen(&mut database, &scanners[2], -30).unwrap();
+        let presence: serde_json::Value = serde_json::from_slice(&outside.payload).unwrap();
+        assert!(presence["room"].is_null());
+
+        let removed = publisher
+            .publication(&WebMessage::DeviceRemoved(device.uuid), &database)
+            .unwrap();
+        assert_eq!(removed.topic, topic);
+        assert!(removed.payload.is_empty());
+
+        let info = AlarmInfo {
+            uuid: uuid::Uuid::new_v4(),
+            ..Default::default()
+        };
+        let alarm = publisher
+            .publication(&WebMessage::Alarm(info.clone()), &database)
+            .unwrap();
+        assert_eq!(alarm.topic, format!("evac/alarm/{}", info.uuid));
+        assert!(alarm.retain && !alarm.payload.is_empty());
+        let stop = publisher
+            .publication(&WebMessage::AlarmStop(info.uuid), &database)
+            .unwrap();
+        assert_eq!(stop.topic, alarm.topic);
+        assert!(stop.retain && stop.payload.is_empty());
+    }
+
+    // Needs a broker on 127.0.0.1:1883, e.g. `mosquitto -p 1883`
+    #[tokio::test]
+    #[ignore]
+    async fn local_broker() {
+        let mut database = Database::default();
+        database.config.mqtt = config::Mqtt {
+            enabled: true,
+            scanners: true,
+            prefix: format!("evac-test-{}", uuid::Uuid::new_v4()),
+            ..Default::default()
+        };
+        let prefix = database.config.mqtt.prefix.clone();
+
+        let (scanner_sender, _scanner_receiver) = mpsc::channel(16);
+        let (scanner_stream, mut stream_receiver) = mpsc::channel(16);
+        let context = crate::context::Context {
+            global_broadcast: broadcast::Sender::new(16),
+            web_broadcast: broadcast::Sender::new(16),
+            scanner_sender,
+            scanner_stream,
+            database,
+            alarms: BTreeMap::new(),
+        };
+        let web_broadcast = context.web_broadcast.clone();
+        let context = std::sync::Arc::new(tokio::sync::RwLock::new(context));
+        tokio::spawn(async move { Bridge::new(context).run().await });
+
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("evac-test", "127.0.0.1", 1883), 16);
+        client
+            .subscribe(format!("{}/alarm/+", prefix), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let (published, mut retained) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let _ = published.send(publish).await;
+                    }
+                    Ok(_) => {}
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        });
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            client
+                .publish(
+                    format!("{}/scanner/010203040506/scan", prefix),
+                    QoS::AtLeastOnce,
+                    false,
+                    vec![1, 2, 3],
+                )
+                .await
+                .unwrap();
+            let _ = web_broadcast.send(WebMessage::Alarm(AlarmInfo::default()));
+        });
+
+        let open = tokio::time::timeout(Duration::from_secs(5), stream_receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(open, Some(StreamEvent::Open { .. })));
+        let frame = stream_receiver.recv().await;
+        assert!(matches!(frame, Some(StreamEvent::Frame { data, .. }) if data == vec![1, 2, 3]));
+
+        let alarm = tokio::time::timeout(Duration::from_secs(5), retained.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(alarm.topic.ends_with(&uuid::Uuid::nil().to_string()));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    io::{BufWriter, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use shared::messages::scanner::ScannerMessage;
+
+// Decoded scanner message as it was received
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Record {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+    pub addr: SocketAddr,
+    pub message: ScannerMessage,
+}
+
+// Records waiting for the writer thread, more are dropped
+const QUEUE: usize = 1024;
+// Buffered records reach the file at least this often
+const FLUSH: Duration = Duration::from_secs(1);
+
+// Append-only capture, every record is MessagePack prefixed by its length
+pub struct Writer {
+    path: PathBuf,
+    file: BufWriter<std::fs::File>,
+    size: u64,
+    // File reaching this size is renamed to <path>.1 and a new one is started, 0 never rotates
+    limit: u64,
+}
+
+impl Writer {
+    pub fn open(path: impl AsRef<Path>, limit: u64) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+            size,
+            limit,
+        })
+    }
+
+    fn append(path: &Path) -> std::io::Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    }
+
+    pub fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let data = rmp_serde::to_vec(record)?;
+        let length = data.len() as u64 + 4;
+        if self.limit > 0 && self.size > 0 && self.size + length > self.limit {
+            self.rotate()?;
+        }
+
+        // Whole record in one write, interrupted capture loses only the tail
+        let mut buf = Vec::with_capacity(data.len() + 4);
+        buf.extend((data.len() as u32).to_be_bytes());
+        buf.extend(data);
+        self.file.write_all(&buf)?;
+        self.size += length;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    // Previous rotated file is replaced
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, rotated)?;
+        self.file = BufWriter::new(Self::append(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    // Disk is written by own thread so receiving never waits for it
+    pub fn spawn(mut self) -> Capture {
+        let (sender, receiver) = mpsc::sync_channel::<Record>(QUEUE);
+        std::thread::spawn(move || loop {
+            let result = match receiver.recv_timeout(FLUSH) {
+                Ok(record) => self.write(&record),
+                Err(mpsc::RecvTimeoutError::Timeout) => self.flush().map_err(Into::into),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    if let Err(err) = self.flush() {
+                        tracing::error!("Unable to flush capture due to: {}", err);
+                    }
+                    break;
+                }
+            };
+            if let Err(err) = result {
+                tracing::error!("Unable to capture message due to: {}", err);
+            }
+        });
+        Capture { sender }
+    }
+}
+
+// Handle of running writer, dropping it flushes and closes the file
+pub struct Capture {
+    sender: mpsc::SyncSender<Record>,
+}
+
+impl Capture {
+    pub fn write(&self, record: Record) {
+        if let Err(mpsc::TrySendError::Full(_)) = self.sender.try_send(record) {
+            tracing::warn!("Capture is behind, message dropped");
+        }
+    }
+}
+
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl Reader<std::io::BufReader<std::fs::File>> {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::new(std::io::BufReader::new(std::fs::File::open(
+            path,
+        )?)))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = anyhow::Result<Record>;
+
+    // Truncated record at the end is the end of capture
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 4];
+        self.inner.read_exact(&mut length).ok()?;
+        let mut data = vec![0u8; u32::from_be_bytes(length) as usize];
+        self.inner.read_exact(&mut data).ok()?;
+        Some(rmp_serde::from_slice(&data).map_err(anyhow::Error::from))
+    }
+}
+
+// Time to wait before next record, speed multiplies real time and 0 does not wait
+pub fn delay(previous: &Record, next: &Record, speed: f64) -> Duration {
+    if speed <= 0.0 {
+        return Duration::ZERO;
+    }
+    let elapsed = (next.timestamp - previous.timestamp)
+        .to_std()
+        .unwrap_or_default();
+    elapsed.div_f64(speed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::messages::scanner::ScannerContent;
+
+    fn record(ms: i64) -> Record {
+        Record {
+            timestamp: DateTime::from_timestamp_millis(ms).unwrap(),
+            addr: "192.168.1.20:3031".parse().unwrap(),
+            message: ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content: ScannerContent::Ping(format!("{}", ms)),
+            },
+        }
+    }
+
+    #[test]
+    fn append_and_read() {
+        let path = std::env::temp_dir().join(format!("evac-capture-{}", uuid::Uuid::new_v4()));
+        let records = vec![record(1_000), record(1_250), record(3_250)];
+
+        // Capture continues after restart
+        let mut writer = Writer::open(&path, 0).unwrap();
+        writer.write(&records[0]).unwrap();
+        writer.flush().unwrap();
+        let mut writer = Writer::open(&path, 0).unwrap();
+        for record in &records[1..] {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // Interrupted last write is skipped
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend([0, 0, 0, 9, 1, 2]);
+        let read: Vec<Record> = Reader::new(data.as_slice())
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        assert_eq!(
+            delay(&records[0], &records[2], 1.0),
+            Duration::from_millis(2250)
+        );
+        assert_eq!(
+            delay(&records[0], &records[2], 10.0),
+            Duration::from_millis(225)
+        );
+        assert_eq!(delay(&records[0], &records[2], 0.0), Duration::ZERO);
+        assert_eq!(delay(&records[2], &records[0], 1.0), Duration::ZERO);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rotation() {
+        let path = std::env::temp_dir().join(format!("evac-capture-{}", uuid::Uuid::new_v4()));
+        let rotated =
+            path.with_file_name(format!("{}.1", path.file_name().unwrap().to_string_lossy()));
+        let records = vec![record(1_000), record(1_250), record(3_250)];
+        let size = rmp_serde::to_vec(&records[0]).unwrap().len() as u64 + 4;
+
+        // Two records fit, third starts a new file
+        let mut writer = Writer::open(&path, size * 2).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let read = |path: &Path| -> Vec<Record> {
+            Reader::open(path)
+                .unwrap()
+                .collect::<anyhow::Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(read(&rotated), records[..2]);
+        assert_eq!(read(&path), records[2..]);
+
+        // Dropped handle flushes what is left
+        let capture = Writer::open(&path, 0).unwrap().spawn();
+        capture.write(records[0].clone());
+        drop(capture);
+        let mut flushed = Vec::new();
+        for _ in 0..100 {
+            flushed = read(&path);
+            if flushed.len() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(flushed, vec![records[2].clone(), records[0].clone()]);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
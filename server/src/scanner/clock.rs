@@ -0,0 +1,62 @@
+use chrono::{DateTime, Duration, Utc};
+
+// Larger jump of the estimate means scanner clock restarted, estimation starts again
+const STEP: i64 = 1000;
+// Older corrected times come from a stale offset, arrival minus age is used instead
+const MAX_DELAY: i64 = 60_000;
+
+// Scanner clock minus server clock in ms, Pong time is taken as the middle of the round trip
+pub fn sample(sent: DateTime<Utc>, received: DateTime<Utc>, time: u64) -> i64 {
+    let middle = sent + (received - sent) / 2;
+    time as i64 - middle.timestamp_millis()
+}
+
+// Smoothed offset, jitter of one round trip moves it only by an eighth
+pub fn update(offset: Option<i64>, sample: i64) -> i64 {
+    match offset {
+        Some(offset) if (sample - offset).abs() <= STEP => offset + (sample - offset) / 8,
+        _ => sample,
+    }
+}
+
+// Server time of a scanner clock reading, never later than its arrival
+pub fn correct(offset: i64, time: u64, received: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let corrected = DateTime::from_timestamp_millis((time as i64).checked_sub(offset)?)?;
+    if corrected > received + Duration::milliseconds(STEP)
+        || corrected < received - Duration::milliseconds(MAX_DELAY)
+    {
+        return None;
+    }
+    Some(corrected.min(received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_estimation() {
+        let sent = DateTime::from_timestamp_millis(1_000_000).unwrap();
+        let received = sent + Duration::milliseconds(40);
+
+        // Scanner counts from boot, answered in the middle of the round trip
+        let offset = sample(sent, received, 5_020);
+        assert_eq!(offset, 5_020 - 1_000_020);
+
+        // Jitter is smoothed, restarted clock replaces the estimate
+        assert_eq!(update(Some(offset), offset + 80), offset + 10);
+        assert_eq!(update(Some(offset), 300), 300);
+        assert_eq!(update(None, offset), offset);
+
+        // Result captured 2 s before it arrived
+        let arrival = received + Duration::milliseconds(10_000);
+        assert_eq!(
+            correct(offset, 13_040, arrival),
+            Some(received + Duration::milliseconds(8_000))
+        );
+        // Small error cannot move result to the future
+        assert_eq!(correct(offset, 15_100, arrival), Some(arrival));
+        assert_eq!(correct(offset, 25_020, arrival), None);
+        assert_eq!(correct(offset, 0, arrival + Duration::hours(1)), None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use crate::database::entities::{EventKind, SensorKind};
+
+use super::parser;
+
+// Values decoded from one advertisement, the first decoder to set a value wins
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Readings {
+    // Name or beacon identifier announced by the device
+    pub identity: Option<String>,
+    // Percent
+    pub battery: Option<u8>,
+    pub button: Option<EventKind>,
+    pub sensors: BTreeMap<SensorKind, i64>,
+}
+
+impl Readings {
+    fn sensor(&mut self, kind: SensorKind, value: i64) {
+        self.sensors.entry(kind).or_insert(value);
+    }
+}
+
+// Decoder of one vendor format, gets every structure of the advertisement payload
+pub trait AdvertisementDecoder: Send + Sync {
+    fn name(&self) -> &str;
+    fn decode(&self, tag: u8, data: &[u8], readings: &mut Readings);
+}
+
+// Decoders by name, device types select theirs in config
+#[derive(Default)]
+pub struct Registry {
+    decoders: Vec<Box<dyn AdvertisementDecoder>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(LocalName));
+        registry.register(Box::new(BtHome));
+        registry.register(Box::new(IBeacon));
+        registry
+    }
+
+    // Decoder with the same name is replaced
+    pub fn register(&mut self, decoder: Box<dyn AdvertisementDecoder>) {
+        self.decoders.retain(|d| d.name() != decoder.name());
+        self.decoders.push(decoder);
+    }
+
+    pub fn decode(&self, names: &[String], data: Vec<u8>) -> Readings {
+        let decoders: Vec<&dyn AdvertisementDecoder> = names
+            .iter()
+            .filter_map(|name| {
+                let decoder = self.decoders.iter().find(|d| d.name() == name);
+                if decoder.is_none() {
+                    tracing::warn!("Unknown advertisement decoder: {}", name);
+                }
+                decoder.map(|d| d.as_ref())
+            })
+            .collect();
+
+        let mut readings = Readings::default();
+        for parsed in parser::Parser::new(data) {
+            for decoder in &decoders {
+                decoder.decode(parsed.tag, &parsed.data, &mut readings);
+            }
+        }
+        readings
+    }
+}
+
+// Short or complete local name
+pub struct LocalName;
+
+impl AdvertisementDecoder for LocalName {
+    fn name(&self) -> &str {
+        "name"
+    }
+
+    fn decode(&self, tag: u8, data: &[u8], readings: &mut Readings) {
+        if tag == 8 || tag == 9 {
+            if let Ok(name) = String::from_utf8(data.to_vec()) {
+                readings.identity.get_or_insert(name);
+            }
+        }
+    }
+}
+
+// BTHome v2 service data, sent by Shelly BLU devices
+pub struct BtHome;
+
+impl BtHome {
+    // Service data UUID 0xfcd2
+    const UUID: [u8; 2] = [210, 252];
+    const ENCRYPTED: u8 = 1;
+
+    fn button(event: u8) -> Option<EventKind> {
+        match event {
+            0 => None,
+            1 => Some(EventKind::ButtonPressed),
+            2 => Some(EventKind::ButtonDoublePressed),
+            3 => Some(EventKind::ButtonTriplePressed),
+            4 => Some(EventKind::ButtonLongPressed),
+            254 => Some(EventKind::ButtonHold),
+            _ => Some(EventKind::Advertisement),
+        }
+    }
+}
+
+impl AdvertisementDecoder for BtHome {
+    fn name(&self) -> &str {
+        "bthome"
+    }
+
+    fn decode(&self, tag: u8, data: &[u8], readings: &mut Readings) {
+        if tag != 22 || !data.starts_with(&Self::UUID) {
+            return;
+        }
+        let Some(info) = data.get(2) else {
+            return;
+        };
+        if info & Self::ENCRYPTED != 0 {
+            return;
+        }
+
+        // Objects are id and little endian value, size is given by the id
+        let mut objects = &data[3..];
+        while let Some((&id, rest)) = objects.split_first() {
+            let size = match id {
+                0x00 | 0x01 | 0x21 | 0x2d | 0x2e | 0x3a => 1,
+                0x02 | 0x03 | 0x0c | 0x3f | 0x45 => 2,
+                0x05 => 3,
+                // Size of unknown object is unknown, rest cannot be read
+                _ => return,
+            };
+            let Some(value) = rest.get(..size) else {
+                return;
+            };
+            objects = &rest[size..];
+
+            let unsigned = value
+                .iter()
+                .rev()
+                .fold(0i64, |result, byte| (result << 8) | *byte as i64);
+            let signed = match size {
+                2 => unsigned as u16 as i16 as i64,
+                _ => unsigned,
+            };
+            match id {
+                0x01 => {
+                    readings.battery.get_or_insert(value[0]);
+                }
+                0x02 => readings.sensor(SensorKind::Temperature, signed * 10),
+                0x03 => readings.sensor(SensorKind::Humidity, unsigned * 10),
+                0x05 => readings.sensor(SensorKind::Illuminance, unsigned * 10),
+                0x0c => readings.sensor(SensorKind::Voltage, unsigned),
+                0x21 => readings.sensor(SensorKind::Motion, unsigned),
+                0x2d => readings.sensor(SensorKind::Window, unsigned),
+                0x2e => readings.sensor(SensorKind::Humidity, unsigned * 1000),
+                0x3a => {
+                    if readings.button.is_none() {
+                        readings.button = Self::button(value[0]);
+                    }
+                }
+                0x3f => readings.sensor(SensorKind::Rotation, signed * 100),
+                0x45 => readings.sensor(SensorKind::Temperature, signed * 100),
+                // Packet id
+                _ => {}
+            }
+        }
+    }
+}
+
+// Apple iBeacon manufacturer data, identity is UUID, major and minor
+pub struct IBeacon;
+
+impl AdvertisementDecoder for IBeacon {
+    fn name(&self) -> &str {
+        "ibeacon"
+    }
+
+    fn decode(&self, tag: u8, data: &[u8], readings: &mut Readings) {
+        if tag != 0xff || data.len() < 25 || data[..4] != [0x4c, 0x00, 0x02, 0x15] {
+            return;
+        }
+        let Ok(uuid) = uuid::Uuid::from_slice(&data[4..20]) else {
+            return;
+        };
+        let major = u16::from_be_bytes([data[20], data[21]]);
+        let minor = u16::from_be_bytes([data[22], data[23]]);
+        readings
+            .identity
+            .get_or_insert(format!("{}:{}:{}", uuid, major, minor));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(names: &[&str], payload: &str) -> Readings {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        Registry::builtin().decode(&names, hex::decode(payload).unwrap())
+    }
+
+    #[test]
+    fn local_name() {
+        // Scan response of Shelly BLU Button1
+        let readings = decode(&["name"], "0a09534242542d30303243");
+        assert_eq!(readings.identity.as_deref(), Some("SBBT-002C"));
+    }
+
+    #[test]
+    fn shelly_button() {
+        // Shelly BLU Button1, single press with full battery
+        let payload = "0201060a16d2fc44002f01643a01";
+        let readings = decode(&["name", "bthome"], payload);
+        assert_eq!(
+            readings,
+            Readings {
+                battery: Some(100),
+                button: Some(EventKind::ButtonPressed),
+                ..Default::default()
+            }
+        );
+
+        // Hold, and no press in a periodic advertisement
+        let readings = decode(&["bthome"], "0201060a16d2fc44003001633afe");
+        assert_eq!(readings.button, Some(EventKind::ButtonHold));
+        assert_eq!(readings.battery, Some(99));
+        let readings = decode(&["bthome"], "0201060a16d2fc44003101633a00");
+        assert_eq!(readings.button, None);
+
+        // Decoder not configured for the device type
+        assert_eq!(decode(&["name"], payload), Readings::default());
+    }
+
+    #[test]
+    fn shelly_sensors() {
+        // Shelly BLU H&T, 56 % humidity and 20.9 °C
+        let readings = decode(&["bthome"], "0201060d16d2fc4400a301642e3845d100");
+        assert_eq!(readings.battery, Some(100));
+        assert_eq!(
+            readings.sensors,
+            BTreeMap::from_iter([
+                (SensorKind::Humidity, 56_000),
+                (SensorKind::Temperature, 20_900),
+            ])
+        );
+
+        // Shelly BLU Door/Window, open at 200 lux
+        let readings = decode(&["bthome"], "0201061116d2fc44000e016405204e002d013f0000");
+        assert_eq!(
+            readings.sensors,
+            BTreeMap::from_iter([
+                (SensorKind::Illuminance, 200_000),
+                (SensorKind::Window, 1),
+                (SensorKind::Rotation, 0),
+            ])
+        );
+
+        // Encrypted and truncated objects
+        assert_eq!(
+            decode(&["bthome"], "0201060a16d2fc45002f01643a01"),
+            Readings::default()
+        );
+        let readings = decode(&["bthome"], "0201060a16d2fc44000101644502");
+        assert_eq!(readings.battery, Some(100));
+        assert!(readings.sensors.is_empty());
+    }
+
+    #[test]
+    fn ibeacon() {
+        // Estimote beacon, major 1 and minor 2
+        let payload = "0201061aff4c000215b9407f30f5f8466eaff925556b57fe6d00010002c5";
+        let readings = decode(&["name", "bthome", "ibeacon"], payload);
+        assert_eq!(
+            readings.identity.as_deref(),
+            Some("b9407f30-f5f8-466e-aff9-25556b57fe6d:1:2")
+        );
+        assert_eq!(decode(&["name", "bthome"], payload), Readings::default());
+    }
+
+    #[test]
+    fn registry() {
+        struct Fixed;
+        impl AdvertisementDecoder for Fixed {
+            fn name(&self) -> &str {
+                "bthome"
+            }
+            fn decode(&self, _tag: u8, _data: &[u8], readings: &mut Readings) {
+                readings.battery.get_or_insert(1);
+            }
+        }
+
+        // Custom decoder replaces the built-in one, unknown names are skipped
+        let mut registry = Registry::builtin();
+        registry.register(Box::new(Fixed));
+        let names = vec![String::from("unknown"), String::from("bthome")];
+        let readings =
+            registry.decode(&names, hex::decode("0201060a16d2fc44002f01643a01").unwrap());
+        assert_eq!(readings.battery, Some(1));
+        assert_eq!(readings.button, None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Instant};
+
+// Unknown sources tracked one by one, the rest shares the overall unknown bucket only
+pub const MAX_SOURCES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    // Packets per second and how many can come at once
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Limit {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(limit: &Limit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst,
+            last: now,
+        }
+    }
+
+    pub fn take(&mut self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * limit.rate >= limit.burst
+    }
+}
+
+struct Source {
+    bucket: Bucket,
+    scanner: Option<uuid::Uuid>,
+    // Packets dropped since the last report
+    dropped: u64,
+}
+
+// Dropped packets of one source since the last report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttled {
+    pub source: SocketAddr,
+    pub scanner: Option<uuid::Uuid>,
+    pub dropped: u64,
+}
+
+pub struct RateLimiter {
+    pub source: Limit,
+    pub unknown: Limit,
+    sources: BTreeMap<SocketAddr, Source>,
+    unknown_bucket: Bucket,
+    unknown_dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new(source: Limit, unknown: Limit) -> Self {
+        RateLimiter {
+            unknown_bucket: Bucket::new(&unknown, Instant::now()),
+            source,
+            unknown,
+            sources: BTreeMap::new(),
+            unknown_dropped: 0,
+        }
+    }
+
+    // Decide about datagram before it is decoded, scanner is known for registered sources
+    pub fn allow(&mut self, addr: SocketAddr, scanner: Option<uuid::Uuid>, now: Instant) -> bool {
+        if scanner.is_none() && !self.unknown_bucket.take(&self.unknown, now) {
+            self.unknown_dropped += 1;
+            if let Some(source) = self.sources.get_mut(&addr) {
+                source.dropped += 1;
+            }
+            return false;
+        }
+
+        if !self.sources.contains_key(&addr) {
+            // Unknown packet already passed the overall bucket
+            if self.sources.len() >= MAX_SOURCES {
+                return true;
+            }
+            self.sources.insert(
+                addr,
+                Source {
+                    bucket: Bucket::new(&self.source, now),
+                    scanner,
+                    dropped: 0,
+                },
+            );
+        }
+
+        let limit = self.source;
+        let source = self.sources.get_mut(&addr).expect("source is inserted");
+        source.scanner = scanner.or(source.scanner);
+        if source.bucket.take(&limit, now) {
+            true
+        } else {
+            source.dropped += 1;
+            false
+        }
+    }
+
+    // Report throttled sources and forget idle ones
+    pub fn report(&mut self, now: Instant) -> (Vec<Throttled>, u64) {
+        let limit = self.source;
+        let mut throttled = Vec::new();
+
+        self.sources.retain(|addr, source| {
+            if source.dropped > 0 {
+                throttled.push(Throttled {
+                    source: *addr,
+                    scanner: source.scanner,
+                    dropped: source.dropped,
+                });
+                source.dropped = 0;
+                return true;
+            }
+            !source.bucket.is_full(&limit, now)
+        });
+
+        (throttled, std::mem::take(&mut self.unknown_dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 10], port))
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limit = Limit::new(10, 2);
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&limit, now);
+
+        assert!(bucket.take(&limit, now));
+        assert!(bucket.take(&limit, now));
+        assert!(!bucket.take(&limit, now));
+        assert!(bucket.take(&limit, now + Duration::from_millis(100)));
+        assert!(!bucket.take(&limit, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn known_and_unknown_sources() {
+        let scanner = uuid::Uuid::new_v4();
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Limit::new(1, 2), Limit::new(1, 4));
+
+        // Each source has its own bucket
+        assert!(limiter.allow(addr(1), Some(scanner), now));
+        assert!(limiter.allow(addr(1), Some(scanner), now));
+        assert!(!limiter.allow(addr(1), Some(scanner), now));
+        assert!(limiter.allow(addr(2), None, now));
+        assert!(limiter.allow(addr(2), None, now));
+        assert!(!limiter.allow(addr(2), None, now));
+
+        // Unknown sources share the overall bucket, known ones do not
+        assert!(limiter.allow(addr(3), None, now));
+        assert!(!limiter.allow(addr(4), None, now));
+        assert!(limiter.allow(addr(5), Some(uuid::Uuid::new_v4()), now));
+
+        let (throttled, unknown) = limiter.report(now);
+        assert_eq!(unknown, 1);
+        assert_eq!(
+            throttled,
+            vec![
+                Throttled {
+                    source: addr(1),
+                    scanner: Some(scanner),
+                    dropped: 1
+                },
+                Throttled {
+                    source: addr(2),
+                    scanner: None,
+                    dropped: 1
+                },
+            ]
+        );
+
+        // Idle sources with full buckets are forgotten
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.report(later), (Vec::new(), 0));
+        assert!(limiter.sources.is_empty());
+    }
+}
@@ -1,11 +1,16 @@
 use core::time;
-use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use mail_send::mail_auth::arc::parse;
 use serde::de;
 use serde_json::ser;
 use shared::messages::scanner::{
-    self, ScannerContent, ScannerEvent, ScannerMessage, State,
+    self, capability, ScanDevice, ScannerContent, ScannerEvent, ScannerMessage, ScannerWrapped,
+    PROTOCOL_VERSION,
 };
 use tokio::{
     net::UdpSocket,
@@ -16,82 +21,821 @@ use uuid::Uuid;
 
 use crate::{
     context::Context,
-    database::{entities::DeviceActivity, LoadSave},
+    database::{config::ScannerSecurity, entities},
     message::web::{self, WebMessage},
 };
 
+pub mod capture;
+mod clock;
+pub mod decoder;
+mod limit;
 mod map;
 mod parser;
+mod pending;
+mod replay;
+pub mod stream;
+mod update;
+
+// Addresses which failed to bind are tried again after this delay
+const BIND_RETRY: Duration = Duration::from_secs(10);
 
 pub struct Scanner {
     context: super::context::ContextWrapped,
     scanners: map::ScannerMap,
-    socket: Option<UdpSocket>,
-    broadcast: SocketAddr,
+    pending: pending::PendingMap,
+    // Outstanding ping payload and send time per scanner
+    pings: BTreeMap<uuid::Uuid, (String, Instant)>,
+    updates: update::UpdateMap,
+    decoders: decoder::Registry,
+    replay: replay::ReplayMap,
+    // Highest sequences in the sequence file, captured messages are not accepted after restart
+    sequences: BTreeMap<uuid::Uuid, u64>,
+    limiter: limit::RateLimiter,
+    // One socket per configured address, bound on first receive
+    sockets: Vec<UdpSocket>,
+    // Addresses which failed to bind and when they are tried again, none before first receive
+    unbound: Vec<SocketAddr>,
+    bind_retry: Option<Instant>,
+    streams: stream::StreamMap,
+    broadcast: Vec<SocketAddr>,
+    // Received messages are recorded for replay when capture is configured
+    capture: Option<capture::Capture>,
+    // Replay runs on the recorded time
+    clock: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Known sender of a received message, sequence is verified by the signature
+struct Origin {
+    scanner: uuid::Uuid,
+    sequence: u64,
+    required: bool,
+    // Highest sequence saved before restart
+    saved: u64,
 }
 
 impl Scanner {
-    pub fn new(context: super::context::ContextWrapped, broadcast: SocketAddr) -> Self {
+    pub async fn new(context: super::context::ContextWrapped, broadcast: Vec<SocketAddr>) -> Self {
+        let (limiter, capture, sequences) = {
+            let base = &context.read().await.database.config.base;
+            (
+                limit::RateLimiter::new(
+                    limit::Limit::new(base.scanner_rate, base.scanner_burst),
+                    limit::Limit::new(base.scanner_unknown_rate, base.scanner_unknown_burst),
+                ),
+                Self::open_capture(&base.scanner_capture, base.scanner_capture_limit),
+                crate::util::json_load(&base.sequence_path()).unwrap_or_default(),
+            )
+        };
         Self {
             broadcast,
             context,
             scanners: map::ScannerMap::new(),
-            socket: None,
+            pending: pending::PendingMap::new(),
+            pings: BTreeMap::new(),
+            updates: update::UpdateMap::new(),
+            decoders: decoder::Registry::builtin(),
+            replay: replay::ReplayMap::new(),
+            sequences,
+            limiter,
+            sockets: Vec::new(),
+            unbound: Vec::new(),
+            bind_retry: None,
+            streams: stream::StreamMap::new(),
+            capture,
+            clock: None,
         }
     }
 
-    pub async fn send(&self, event: ScannerEvent) -> anyhow::Result<bool> {
-        let data = rmp_serde::to_vec(&event.message)?;
-        //let wrapped = rmp_serde::to_vec(&ScannerWrapped::Plain(data))?;
+    // Capture is opened once, its writer thread lives as long as the scanner
+    fn open_capture(path: &str, limit: u64) -> Option<capture::Capture> {
+        if path.is_empty() {
+            return None;
+        }
 
-        if let Some(socket) = self.socket.as_ref() {
-            tracing::info!("Scanner: {:?}", event.scanner);
+        match capture::Writer::open(path, limit) {
+            Ok(writer) => {
+                tracing::info!("Capturing scanner traffic to {}", path);
+                Some(writer.spawn())
+            }
+            Err(err) => {
+                tracing::error!("Unable to open capture {} due to: {}", path, err);
+                None
+            }
+        }
+    }
 
-            if let Some(uuid) = event.scanner {
-                if let Some(addr) = self.scanners.get_addr(&uuid) {
-                    tracing::info!("Sending message: {:?}", data);
-                    return Ok(socket.send_to(&data, addr).await.is_ok());
-                } else {
-                    tracing::error!("Unable to find device: {:?}", event);
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.unwrap_or_else(chrono::offset::Utc::now)
+    }
+
+    // Process recorded message as if it was just received at the recorded time
+    pub async fn replay(&mut self, record: capture::Record) -> anyhow::Result<()> {
+        self.clock = Some(record.timestamp);
+        let result = self.process_socket(record.addr, record.message).await;
+        self.clock = None;
+        result
+    }
+
+    pub async fn send(&mut self, event: ScannerEvent) -> anyhow::Result<bool> {
+        if !pending::PendingMap::is_tracked(&event.message.content) {
+            return self.transmit(&event).await;
+        }
+
+        let (timeout, events) = {
+            let context = self.context.read().await;
+            let timeout = Duration::from_millis(context.database.config.base.scanner_ack_timeout);
+
+            let events = if event.scanner.is_some() {
+                vec![event]
+            } else {
+                // Each scanner confirms its own copy of the command
+                context
+                    .database
+                    .data
+                    .scanners
+                    .keys()
+                    .map(|uuid| ScannerEvent {
+                        scanner: Some(*uuid),
+                        message: ScannerMessage {
+                            uuid: uuid::Uuid::new_v4(),
+                            content: event.message.content.clone(),
+                        },
+                    })
+                    .collect()
+            };
+
+            // Only scanners which announce acknowledgements are waited for
+            let events: Vec<(ScannerEvent, bool)> = events
+                .into_iter()
+                .map(|event| {
+                    let tracked = event
+                        .scanner
+                        .and_then(|uuid| context.database.data.scanners.get(&uuid))
+                        .is_some_and(|s| s.has_capability(capability::ACK));
+                    (event, tracked)
+                })
+                .collect();
+            (timeout, events)
+        };
+
+        let mut result = true;
+        for (event, tracked) in events {
+            result &= self.transmit(&event).await?;
+            if let (Some(uuid), ScannerContent::Update { version, .. }) =
+                (event.scanner, &event.message.content)
+            {
+                let progress = self.updates.start(uuid, version.clone());
+                self.report_update(progress).await;
+            }
+            if tracked {
+                self.pending.push(event, timeout);
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.pending.next()
+    }
+
+    // Retransmit unconfirmed commands and report scanners which did not confirm them
+    pub async fn retry(&mut self) {
+        let (timeout, retries, web_broadcast) = {
+            let context = self.context.read().await;
+            let base = &context.database.config.base;
+            (
+                Duration::from_millis(base.scanner_ack_timeout),
+                base.scanner_ack_retries,
+                context.web_broadcast.clone(),
+            )
+        };
+
+        let (resend, failed) = self.pending.due(timeout, retries);
+        for event in resend {
+            tracing::warn!("Retransmitting unconfirmed message: {:?}", event);
+            if let Err(err) = self.transmit(&event).await {
+                tracing::error!("{}", err);
+            }
+        }
+
+        for (event, attempts) in failed {
+            tracing::error!("Scanner did not confirm message: {:?}", event);
+            if let (Some(uuid), ScannerContent::Update { .. }) =
+                (event.scanner, &event.message.content)
+            {
+                if let Some(progress) = self.updates.fail(uuid, "Update was not confirmed") {
+                    let _ = web_broadcast.send(WebMessage::FirmwareProgress(progress));
+                }
+            }
+            let _ = web_broadcast.send(WebMessage::ScannerUnconfirmed(web::ScannerUnconfirmed {
+                scanner: event.scanner.unwrap_or_default(),
+                message: event.message.uuid,
+                attempts,
+                error: None,
+            }));
+        }
+    }
+
+    async fn report_update(&self, progress: web::FirmwareProgress) {
+        tracing::info!("Firmware update: {:?}", progress);
+        let context = self.context.read().await;
+        let _ = context
+            .web_broadcast
+            .send(WebMessage::FirmwareProgress(progress));
+    }
+
+    // Ping registered scanners and update their online state
+    pub async fn liveness(&mut self) {
+        let now = chrono::offset::Utc::now();
+        let mut offline = Vec::new();
+
+        let scanners: Vec<(uuid::Uuid, bool)> = {
+            let mut context = self.context.write().await;
+            let timeout = context.database.config.base.scanner_timeout;
+            let web_broadcast = context.web_broadcast.clone();
+
+            // Limits changed in config apply from the next round
+            let base = &context.database.config.base;
+            self.limiter.source = limit::Limit::new(base.scanner_rate, base.scanner_burst);
+            self.limiter.unknown =
+                limit::Limit::new(base.scanner_unknown_rate, base.scanner_unknown_burst);
+
+            let (throttled, unknown) = self.limiter.report(Instant::now());
+            for throttled in throttled {
+                tracing::warn!("Throttled scanner source: {:?}", throttled);
+                if let Some(scanner) = throttled
+                    .scanner
+                    .and_then(|uuid| context.database.data.scanners.get_mut(&uuid))
+                {
+                    scanner.diagnostics.rate_limited += throttled.dropped;
                 }
+                let _ = web_broadcast.send(WebMessage::ScannerThrottled(web::ScannerThrottled {
+                    source: Some(throttled.source),
+                    scanner: throttled.scanner,
+                    dropped: throttled.dropped,
+                }));
+            }
+            if unknown > 0 {
+                tracing::warn!("Throttled unknown scanner sources: {}", unknown);
+                let _ = web_broadcast.send(WebMessage::ScannerThrottled(web::ScannerThrottled {
+                    source: None,
+                    scanner: None,
+                    dropped: unknown,
+                }));
+            }
+
+            let firmware_timeout =
+                Duration::from_secs(context.database.config.base.firmware_timeout);
+            for progress in self.updates.expired(firmware_timeout) {
+                tracing::error!("Firmware update timed out: {:?}", progress);
+                let _ = web_broadcast.send(WebMessage::FirmwareProgress(progress));
+            }
+
+            for scanner in context.database.data.scanners.values_mut() {
+                let online = (now - scanner.last_activity).num_seconds() < timeout;
+                if scanner.online != online {
+                    scanner.online = online;
+                    if online {
+                        tracing::info!("Scanner is online: {}", scanner.name);
+                        let _ = web_broadcast.send(WebMessage::ScannerOnline(scanner.uuid));
+                    } else {
+                        tracing::warn!("Scanner is offline: {}", scanner.name);
+                        let _ = web_broadcast.send(WebMessage::ScannerOffline(scanner.uuid));
+                        offline.push(scanner.clone());
+                    }
+                }
+            }
+
+            if !offline.is_empty() {
+                Self::notify_offline(&context, offline);
+            }
+
+            context
+                .database
+                .data
+                .scanners
+                .values()
+                .map(|scanner| (scanner.uuid, scanner.has_capability(capability::CLOCK)))
+                .collect()
+        };
+
+        for (uuid, clock) in scanners {
+            let payload = uuid::Uuid::new_v4().to_string();
+            let content = if clock {
+                ScannerContent::TimedPing(payload.clone())
             } else {
-                return Ok(socket.send_to(&data, self.broadcast).await.is_ok());
+                ScannerContent::Ping(payload.clone())
+            };
+            let event = ScannerEvent {
+                scanner: Some(uuid),
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content,
+                },
+            };
+
+            if let Ok(true) = self.transmit(&event).await {
+                self.pings.insert(uuid, (payload, Instant::now()));
             }
         }
 
-        Ok(false)
+        if self.update_sequences() {
+            if let Err(err) = self.save_sequences().await {
+                tracing::error!("Unable to save scanner sequences: {}", err);
+            }
+        }
     }
 
-    pub async fn recv(&mut self, port: SocketAddr) -> anyhow::Result<bool> {
-        let mut buf = [0u8; 512];
-        if let Some(socket) = self.socket.as_ref() {
-            if let Ok((len, addr)) = socket.recv_from(&mut buf).await {
-                match rmp_serde::from_slice::<shared::messages::scanner::ScannerMessage>(
-                    &buf[..len],
-                ) {
-                    Ok(msg) => {
-                        self.process_socket(addr, msg).await?;
-                        return Ok(true);
+    // Take over highest accepted sequences, true when any of them moved
+    fn update_sequences(&mut self) -> bool {
+        let mut changed = false;
+        for (uuid, top) in self.replay.tops() {
+            let saved = self.sequences.entry(uuid).or_default();
+            if *saved < top {
+                *saved = top;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Sequences are saved in their own small file every liveness round they moved and on stop,
+    // only messages of the last round before a crash could be accepted once more
+    pub async fn save_sequences(&mut self) -> anyhow::Result<()> {
+        self.update_sequences();
+        let path = self
+            .context
+            .read()
+            .await
+            .database
+            .config
+            .base
+            .sequence_path();
+        let sequences = self.sequences.clone();
+        tokio::task::spawn_blocking(move || crate::util::json_save(&path, &sequences)).await?
+    }
+
+    // Offline scanner is a blind spot, let the configured group know
+    fn notify_offline(context: &Context, scanners: Vec<entities::Scanner>) {
+        let notification = &context.database.config.notification;
+        let Some(offline) = notification.scanner_offline.as_ref() else {
+            return;
+        };
+        let Some(template) = context
+            .database
+            .data
+            .notifications
+            .get(&offline.notification)
+            .cloned()
+        else {
+            tracing::error!("Scanner offline notification does not exist");
+            return;
+        };
+
+        let contacts = context.database.data.get_contacts_by_group(offline.group);
+        let infos: Vec<web::AlarmInfo> = scanners
+            .into_iter()
+            .map(|scanner| {
+                let room = scanner
+                    .room
+                    .and_then(|room| context.database.data.rooms.get(&room));
+                let location =
+                    room.and_then(|room| context.database.data.locations.get(&room.location));
+
+                web::AlarmInfo {
+                    uuid: uuid::Uuid::new_v4(),
+                    scanner: scanner.name,
+                    room: room.map(|r| r.name.clone()).unwrap_or_default(),
+                    location: location.map(|l| l.name.clone()).unwrap_or_default(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let notification = notification.clone();
+        tokio::spawn(async move {
+            for info in infos {
+                for contact in contacts.iter() {
+                    if let Err(err) = notification
+                        .send_alarm(contact.clone(), template.clone(), info.clone())
+                        .await
+                    {
+                        tracing::error!("Unable to send offline notification: {}", err);
                     }
-                    Err(err) => {
-                        tracing::error!("{:?} {:?}", err, &buf[..len]);
-                        return Ok(false);
+                }
+            }
+        });
+    }
+
+    async fn transmit(&self, event: &ScannerEvent) -> anyhow::Result<bool> {
+        if !self.sockets.is_empty() || !self.streams.is_empty() {
+            tracing::info!("Scanner: {:?}", event.scanner);
+
+            let (security, targets) = self.targets(event).await;
+            let mut result = !targets.is_empty();
+
+            for (addr, scanner) in targets {
+                let message = match scanner.as_ref() {
+                    Some(scanner) => match scanner.adapt(&event.message) {
+                        Some(message) => message,
+                        None => {
+                            tracing::debug!("Message is not supported by: {}", scanner.name);
+                            result = false;
+                            continue;
+                        }
+                    },
+                    None => event.message.clone(),
+                };
+
+                let data = Self::wrap(&message, scanner.as_ref(), &security)?;
+                tracing::info!("Sending message: {:?}", data);
+                if self.streams.contains(&addr) {
+                    result &= self.streams.send(&addr, data);
+                    continue;
+                }
+                match self.socket_for(addr) {
+                    Some((socket, addr)) => {
+                        Self::multicast_if(socket, addr);
+                        result &= socket.send_to(&data, addr).await.is_ok()
+                    }
+                    None => {
+                        tracing::debug!("No socket for address: {}", addr);
+                        result = false;
                     }
                 }
+            }
+
+            return Ok(result);
+        }
+
+        Ok(false)
+    }
+
+    // IPv6 multicast leaves through the interface given by scope id of the group, 0 is the default
+    fn multicast_if(socket: &UdpSocket, addr: SocketAddr) {
+        if let SocketAddr::V6(v6) = addr {
+            if v6.ip().is_multicast() {
+                if let Err(err) = socket2::SockRef::from(socket).set_multicast_if_v6(v6.scope_id())
+                {
+                    tracing::error!(
+                        "Unable to set multicast interface of {} due to: {}",
+                        addr,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    // Socket of the same family, IPv4 target falls back to mapped address on dual-stack socket
+    fn socket_for(&self, addr: SocketAddr) -> Option<(&UdpSocket, SocketAddr)> {
+        if let Some(socket) = self.sockets.iter().find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv4() == addr.is_ipv4())
+        }) {
+            return Some((socket, addr));
+        }
+
+        match addr {
+            SocketAddr::V4(v4) => Some((
+                self.sockets.first()?,
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            )),
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    // Encode message for a scanner, scanners with key get signed envelope
+    // and scanners with public key get encrypted envelope
+    fn wrap(
+        message: &ScannerMessage,
+        scanner: Option<&entities::Scanner>,
+        security: &ScannerSecurity,
+    ) -> anyhow::Result<Vec<u8>> {
+        let public_key = scanner.and_then(|s| s.public_key());
+        let wrapped = match (scanner.and_then(|s| s.key()), security) {
+            (Some(key), _) => ScannerWrapped::hashed(message, &key)?,
+            // Legacy firmware understands only bare messages
+            (None, ScannerSecurity::Mixed) if public_key.is_none() => {
+                return Ok(rmp_serde::to_vec(message)?)
+            }
+            (None, _) => ScannerWrapped::plain(message)?,
+        };
+
+        Ok(match public_key {
+            Some(public_key) => wrapped.encrypt(&public_key)?.to_vec()?,
+            None => wrapped.to_vec()?,
+        })
+    }
+
+    // Resolve destination addresses and scanners for event
+    async fn targets(
+        &self,
+        event: &ScannerEvent,
+    ) -> (
+        ScannerSecurity,
+        Vec<(SocketAddr, Option<entities::Scanner>)>,
+    ) {
+        let context = self.context.read().await;
+        let security = context.database.config.base.scanner_security.clone();
+        let scanners = &context.database.data.scanners;
+
+        let targets = if let Some(uuid) = event.scanner {
+            if let Some(addr) = scanners.get(&uuid).and_then(|s| self.address(s)) {
+                vec![(addr, scanners.get(&uuid).cloned())]
             } else {
-                tokio::time::sleep(Duration::MAX).await;
+                tracing::error!("Unable to find device: {:?}", event);
+                Vec::new()
+            }
+        } else if event.message.content == ScannerContent::Hello {
+            // Routers drop local broadcast, reach known scanners and routed subnets directly
+            let mut targets: Vec<(SocketAddr, Option<entities::Scanner>)> = Vec::new();
+            for addr in self
+                .broadcast
+                .iter()
+                .chain(&context.database.config.base.discovery_broadcasts)
+            {
+                let addr = *addr;
+                if !targets.iter().any(|(a, _)| *a == addr) {
+                    targets.push((addr, None));
+                }
             }
+            targets.extend(
+                scanners
+                    .values()
+                    .filter_map(|s| Some((self.address(s)?, Some(s.clone())))),
+            );
+            targets
         } else {
-            if let Ok(udp) = tokio::net::UdpSocket::bind(port).await {
-                tracing::info!("Server is initialized...");
-                if let Err(err) = udp.set_broadcast(true) {
-                    tracing::error!("Problem to set broadcast due to: {}", err);
+            // Signed and encrypted messages cannot be broadcasted, send them one by one
+            // Broadcast does not reach scanners connected over WebSocket either
+            let mut targets: Vec<(SocketAddr, Option<entities::Scanner>)> = scanners
+                .values()
+                .filter_map(|s| Some((self.address(s)?, s)))
+                .filter(|(addr, s)| {
+                    s.key.is_some()
+                        || s.public_key.is_some()
+                        || security != ScannerSecurity::Mixed
+                        || self.streams.contains(addr)
+                })
+                .map(|(addr, s)| (addr, Some(s.clone())))
+                .collect();
+
+            if security == ScannerSecurity::Mixed {
+                targets.extend(self.broadcast.iter().map(|addr| (*addr, None)));
+            }
+            targets
+        };
+
+        (security, targets)
+    }
+
+    // Last seen address, stored one is used before the scanner talks to us
+    fn address(&self, scanner: &entities::Scanner) -> Option<SocketAddr> {
+        self.scanners
+            .get_addr(&scanner.uuid)
+            .or_else(|| scanner.addr())
+    }
+
+    // Decrypt and verify envelope of received datagram against the scanner key
+    async fn open(
+        &self,
+        addr: &SocketAddr,
+        data: &[u8],
+    ) -> anyhow::Result<(ScannerMessage, Option<Origin>)> {
+        let context = self.context.read().await;
+        let base = &context.database.config.base;
+
+        let wrapped = ScannerWrapped::from_slice(data)?;
+        let encrypted = wrapped.is_encrypted();
+        let wrapped = if encrypted {
+            wrapped.decrypt(&hex::decode(&base.scanner_secret)?)?
+        } else {
+            wrapped
+        };
+        let message = wrapped.peek()?;
+
+        // New scanner has no key yet, its plain Register can only make it wait for adoption
+        let enrolling = wrapped.is_plain()
+            && matches!(&message.content, ScannerContent::Register { mac, .. }
+                if !context.database.data.scanners.values().any(|s| s.mac.eq(mac)));
+
+        // Anyone may open a WebSocket, unsigned frame there could take over an unkeyed scanner
+        if wrapped.is_plain()
+            && !enrolling
+            && self.streams.contains(addr)
+            && !self.streams.is_trusted(addr)
+        {
+            anyhow::bail!("Unsigned frame from untrusted stream")
+        }
+
+        match base.scanner_security {
+            ScannerSecurity::Encrypted if !encrypted && !enrolling => {
+                anyhow::bail!("Unencrypted message")
+            }
+            ScannerSecurity::Hashed | ScannerSecurity::Encrypted
+                if wrapped.is_plain() && !enrolling =>
+            {
+                anyhow::bail!("Unsigned message")
+            }
+            _ => {}
+        }
+
+        let scanner = context
+            .database
+            .data
+            .scanners
+            .values()
+            .find(|s| match &message.content {
+                ScannerContent::Register { mac, .. } => s.mac.eq(mac),
+                _ => s.addr() == Some(*addr),
+            });
+        let origin = scanner.map(|s| Origin {
+            scanner: s.uuid,
+            sequence: wrapped.sequence(),
+            required: s.key.is_some() && s.has_capability(capability::SEQUENCE),
+            saved: self.sequences.get(&s.uuid).copied().unwrap_or_default(),
+        });
+
+        if wrapped.is_plain() {
+            // Scanner with a key signs everything, plain message is spoofed
+            if scanner.is_some_and(|s| s.key.is_some()) {
+                anyhow::bail!("Unsigned message from keyed scanner");
+            }
+            return Ok((message, origin));
+        }
+
+        let key = scanner.and_then(|s| s.key());
+        Ok((wrapped.open(key.as_deref())?, origin))
+    }
+
+    async fn count_replay(&self, scanner: uuid::Uuid, replay: replay::Replay) {
+        let mut context = self.context.write().await;
+        if let Some(scanner) = context.database.data.scanners.get_mut(&scanner) {
+            let diagnostics = &mut scanner.diagnostics;
+            match replay {
+                replay::Replay::Duplicate => diagnostics.duplicate += 1,
+                replay::Replay::Stale => diagnostics.stale += 1,
+                replay::Replay::Unsequenced => diagnostics.unsequenced += 1,
+            }
+        }
+    }
+
+    // Bind every address, IPv6 socket is left dual-stack only when no IPv4 address is bound
+    async fn bind(&mut self, ports: &[SocketAddr]) {
+        let hops = self
+            .context
+            .read()
+            .await
+            .database
+            .config
+            .base
+            .scanner_multicast_hops;
+        let only_v6 = ports.iter().any(|port| port.is_ipv4());
+
+        // First round binds every address, later ones only those which failed
+        let pending = match self.bind_retry {
+            Some(_) => std::mem::take(&mut self.unbound),
+            None => ports.to_vec(),
+        };
+        for port in pending {
+            match Self::bind_socket(port, only_v6, hops) {
+                Ok(udp) => {
+                    tracing::info!("Server is initialized on {}...", port);
+                    self.sockets.push(udp);
                 }
-                self.socket = Some(udp);
+                Err(err) => {
+                    tracing::error!("Unable to bind {} due to: {}", port, err);
+                    self.unbound.push(port);
+                }
+            }
+        }
+        self.bind_retry = Some(Instant::now() + BIND_RETRY);
+    }
+
+    fn bind_socket(port: SocketAddr, only_v6: bool, hops: u32) -> std::io::Result<UdpSocket> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(port),
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        if port.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+            socket.set_multicast_hops_v6(hops)?;
+        }
+        if let Err(err) = socket.set_broadcast(true) {
+            tracing::error!("Problem to set broadcast due to: {}", err);
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&port.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    pub async fn recv(&mut self, ports: &[SocketAddr]) -> anyhow::Result<bool> {
+        let due = self.bind_retry.map_or(true, |retry| {
+            !self.unbound.is_empty() && retry <= Instant::now()
+        });
+        if due {
+            self.bind(ports).await;
+            return Ok(false);
+        }
+
+        // Bound sockets are read while the failed addresses wait for the next attempt
+        let retry = self.bind_retry.filter(|_| !self.unbound.is_empty());
+        let retry = async move {
+            match retry {
+                Some(retry) => tokio::time::sleep_until(retry.into()).await,
+                None => std::future::pending().await,
             }
+        };
+        if self.sockets.is_empty() {
+            retry.await;
+            return Ok(false);
         }
 
-        return Ok(false);
+        let received = tokio::select! {
+            received = futures::future::select_all(self.sockets.iter().map(|socket| {
+                Box::pin(async move {
+                    let mut buf = [0u8; 2048];
+                    let (len, addr) = socket.recv_from(&mut buf).await?;
+                    Ok::<_, std::io::Error>((buf[..len].to_vec(), addr))
+                })
+            })) => received.0,
+            _ = retry => return Ok(false),
+        };
+
+        match received {
+            Ok((buf, addr)) => self.receive(crate::util::canonical_addr(addr), buf).await,
+            // Error of one socket leaves the others to be read on the next call
+            Err(err) => {
+                tracing::error!("Unable to receive from scanner socket due to: {}", err);
+                Ok(false)
+            }
+        }
+    }
+
+    // Connection changes and frames of scanners connected over WebSocket
+    pub async fn stream(&mut self, event: stream::StreamEvent) -> anyhow::Result<bool> {
+        match event {
+            stream::StreamEvent::Open {
+                addr,
+                sender,
+                trusted,
+            } => {
+                tracing::info!("Scanner stream connected from {}", addr);
+                self.streams.open(addr, sender, trusted);
+                Ok(false)
+            }
+            stream::StreamEvent::Frame { addr, data } => self.receive(addr, data).await,
+            stream::StreamEvent::Close { addr } => {
+                tracing::info!("Scanner stream disconnected from {}", addr);
+                self.streams.close(&addr);
+                Ok(false)
+            }
+        }
+    }
+
+    // Datagram or stream frame from scanner
+    async fn receive(&mut self, addr: SocketAddr, buf: Vec<u8>) -> anyhow::Result<bool> {
+        // Flood is dropped before any decoding or locking
+        let scanner = self.scanners.get_uuid(&addr);
+        if !self.limiter.allow(addr, scanner, Instant::now()) {
+            return Ok(false);
+        }
+
+        match self.open(&addr, &buf).await {
+            Ok((msg, origin)) => {
+                if let Some(origin) = origin {
+                    if let Err(replay) = self.replay.check(
+                        origin.scanner,
+                        origin.sequence,
+                        origin.required,
+                        origin.saved,
+                    ) {
+                        tracing::warn!("Rejected packet from {}: {}", addr, replay);
+                        self.count_replay(origin.scanner, replay).await;
+                        return Ok(false);
+                    }
+                }
+
+                if let Some(capture) = &self.capture {
+                    capture.write(capture::Record {
+                        timestamp: chrono::offset::Utc::now(),
+                        addr,
+                        message: msg.clone(),
+                    });
+                }
+
+                self.process_socket(addr, msg).await?;
+                Ok(true)
+            }
+            Err(err) => {
+                tracing::warn!("Rejected packet from {}: {} {:?}", addr, err, &buf);
+                Ok(false)
+            }
+        }
     }
 
     pub async fn get_event(
@@ -99,14 +843,42 @@ impl Scanner {
         socket: &SocketAddr,
         msg: shared::messages::scanner::ScannerMessage,
     ) -> Option<ScannerEvent> {
-        let ip = socket.ip().to_string();
+        let socket = &crate::util::canonical_addr(*socket);
+        let ip = crate::util::format_ip(socket);
         let port = socket.port();
-        let now = chrono::offset::Utc::now();
+        let now = self.now();
 
         let mut context = self.context.write().await;
 
+        let min_protocol = context.database.config.base.scanner_protocol;
+
         // Reaction to register msg
-        if let ScannerContent::Register { mac } = &msg.content {
+        if let ScannerContent::Register {
+            mac,
+            protocol,
+            firmware,
+            capabilities,
+        } = &msg.content
+        {
+            if *protocol < min_protocol {
+                tracing::warn!(
+                    "Refusing scanner {} with protocol {}, required {}",
+                    hex::encode(mac),
+                    protocol,
+                    min_protocol
+                );
+                return None;
+            }
+            if *protocol > PROTOCOL_VERSION {
+                tracing::warn!(
+                    "Scanner {} uses newer protocol {}, downgrading to {}",
+                    hex::encode(mac),
+                    protocol,
+                    PROTOCOL_VERSION
+                );
+            }
+            let protocol = (*protocol).min(PROTOCOL_VERSION);
+
             // Mac exists
             if let Some(scanner) = context
                 .database
@@ -118,32 +890,88 @@ impl Scanner {
                 scanner.1.last_activity = now;
                 scanner.1.ip = ip;
                 scanner.1.port = port;
+                scanner.1.protocol = protocol;
+                scanner.1.firmware = firmware.clone();
+                scanner.1.capabilities = *capabilities;
+                // Scanner clock could restart with the scanner
+                scanner.1.clock_offset = None;
+
+                self.scanners.set(*scanner.0, *socket);
 
                 return Some(ScannerEvent {
                     message: msg,
                     scanner: Some(scanner.0.clone()),
                 });
             } else {
-                // Mac dost not exists
-                let uuid = uuid::Uuid::new_v4();
-                context.database.data.scanners.insert(
-                    uuid,
-                    crate::database::entities::Scanner {
-                        uuid,
-                        ip: ip.clone(),
-                        port: port.clone(),
-                        mac: mac.clone(),
-                        room: None,
-                        name: format!("Scanner: {}", hex::encode(mac)),
-                        last_activity: now,
-                        ..Default::default()
-                    },
-                );
+                // Mac dost not exists, scanner waits for adoption by admin
+                let web_broadcast = context.web_broadcast.clone();
+                match context
+                    .database
+                    .data
+                    .pending_scanners
+                    .values_mut()
+                    .find(|s| s.mac.eq(mac))
+                {
+                    Some(pending) if pending.rejected => {
+                        tracing::debug!("Ignoring rejected scanner: {}", hex::encode(mac));
+                    }
+                    Some(pending) => {
+                        pending.last_seen = now;
+                        pending.ip = ip;
+                        pending.port = port;
+                        pending.protocol = protocol;
+                        pending.firmware = firmware.clone();
+                        pending.capabilities = *capabilities;
+                    }
+                    None => {
+                        let limit = context.database.config.base.scanner_pending_limit;
+                        let pending_scanners = &mut context.database.data.pending_scanners;
+                        // Rejected scanners are kept, otherwise they would wait again
+                        while pending_scanners.len() >= limit {
+                            let Some(oldest) = pending_scanners
+                                .values()
+                                .filter(|s| !s.rejected)
+                                .min_by_key(|s| s.last_seen)
+                                .map(|s| s.uuid)
+                            else {
+                                tracing::warn!(
+                                    "Ignoring scanner {}, too many pending scanners",
+                                    hex::encode(mac)
+                                );
+                                return None;
+                            };
+                            pending_scanners.remove(&oldest);
+                            let _ = web_broadcast.send(WebMessage::PendingScannerRemoved(oldest));
+                        }
 
-                return Some(ScannerEvent {
-                    scanner: Some(uuid),
-                    message: msg,
-                });
+                        let pending = entities::PendingScanner {
+                            uuid: uuid::Uuid::new_v4(),
+                            ip,
+                            port,
+                            mac: mac.clone(),
+                            first_seen: now,
+                            last_seen: now,
+                            protocol,
+                            firmware: firmware.clone(),
+                            capabilities: *capabilities,
+                            rejected: false,
+                        };
+                        tracing::info!(
+                            "Scanner {} from {} waits for adoption",
+                            hex::encode(mac),
+                            socket
+                        );
+                        let _ =
+                            web_broadcast.send(WebMessage::PendingScannerDetail(pending.clone()));
+                        context
+                            .database
+                            .data
+                            .pending_scanners
+                            .insert(pending.uuid, pending);
+                    }
+                }
+
+                return None;
             }
         }
 
@@ -153,8 +981,13 @@ impl Scanner {
             .data
             .scanners
             .iter_mut()
-            .find(|s| s.1.ip == ip && s.1.port == port)
+            .find(|s| s.1.addr() == Some(*socket))
         {
+            if scanner.1.protocol < min_protocol {
+                tracing::debug!("Ignoring refused scanner: {}", scanner.1.name);
+                return None;
+            }
+
             scanner.1.last_activity = now;
             scanner.1.ip = ip;
             scanner.1.port = port;
@@ -168,6 +1001,7 @@ impl Scanner {
         }
 
         // Unknown device
+        tracing::debug!("Message from unknown scanner: {}", socket);
         None
     }
 
@@ -178,108 +1012,145 @@ impl Scanner {
     ) -> anyhow::Result<()> {
         if let Some(event) = self.get_event(&socket, msg).await {
             match event.message.content {
-                shared::messages::scanner::ScannerContent::Register { mac } => {
+                shared::messages::scanner::ScannerContent::Register { mac, .. } => {
                     tracing::debug!("Received register message: {:?}", mac);
-                    let context = self.context.read().await;
-                    let path = context.database.config.base.data_path.clone();
-                    //context.database.data.save(&path).unwrap();
+                    if let Some(uuid) = event.scanner {
+                        self.pending.registered(uuid);
+                    }
+                    let (scanner, filters) = {
+                        let context = self.context.read().await;
+                        //let path = context.database.config.base.data_path.clone();
+                        //context.database.data.save(&path).unwrap();
 
-                    if let Some(uuid) = &event.scanner {
-                        if let Some(scanner) = context.database.data.scanners.get(uuid) {
+                        let scanner = event
+                            .scanner
+                            .and_then(|uuid| context.database.data.scanners.get(&uuid).cloned());
+                        if let Some(scanner) = &scanner {
                             context.web_broadcast.send(
                                 crate::message::web::WebMessage::ScannerDetail(scanner.clone()),
                             );
-
-                            self.send(ScannerEvent {
-                                scanner: Some(scanner.uuid),
-                                message: ScannerMessage {
-                                    uuid: uuid::Uuid::new_v4(),
-                                    content: ScannerContent::Set(State {
-                                        scan: Some(scanner.scan),
-                                        led: Some(scanner.led),
-                                        buzzer: Some(scanner.buzzer),
-                                    }),
-                                },
-                            })
-                            .await;
                         }
-                    }
-                }
-                shared::messages::scanner::ScannerContent::ScanResult(result) => {
-                    let mut send_device = None;
-                    let mut enabled = false;
-                    let now = chrono::offset::Utc::now();
-
-                    let mut context = self.context.write().await;
-                    // let activity_diff = context.database.config.base.activity_diff.clone();
-
-                    let device_uuid = if let Some(device) = context
-                        .database
-                        .data
-                        .devices
-                        .values_mut()
-                        .find(|d| d.mac == result.mac)
-                    {
-                        device.last_activity = now;
-                        enabled = device.enabled;
-                        device.uuid.clone()
-                    } else {
-                        let uuid = uuid::Uuid::new_v4();
-                        let device = crate::database::entities::Device {
-                            uuid,
-                            name: None,
-                            enabled: false,
-                            mac: result.mac,
-                            battery: None,
-                            last_activity: now,
-                        };
-                        context.database.data.devices.insert(uuid, device.clone());
-                        send_device = Some(device.clone());
-                        device.uuid
+                        (
+                            scanner.map(|s| {
+                                let state = context.scanner_state(&s);
+                                (s, state)
+                            }),
+                            context.database.scanner_filters(),
+                        )
                     };
 
-                    let scanner_uuid = event.scanner.unwrap();
-                    let web_broadcast = context.web_broadcast.clone();
-
-                    if let Some(device) = context.database.data.devices.get(&device_uuid).cloned() {
-                        if self
-                            .process_service(&mut context, scanner_uuid, device.uuid, result.data)
-                            .await
+                    // Context lock must be released, send resolves scanner keys
+                    if let Some((scanner, state)) = scanner {
+                        if let Some(progress) =
+                            self.updates.running(scanner.uuid, &scanner.firmware)
                         {
-                            enabled = device.enabled;
-                            send_device = Some(device.clone());
+                            self.report_update(progress).await;
                         }
-                    }
 
-                    if let Some(device) = send_device {
-                        if device.name.is_some() {
-                            context
-                                .web_broadcast
-                                .send(crate::message::web::WebMessage::DeviceDetail(device));
+                        self.send(ScannerEvent {
+                            scanner: Some(scanner.uuid),
+                            message: ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Set(state),
+                            },
+                        })
+                        .await;
+
+                        for filter in filters {
+                            let _ = self
+                                .send(ScannerEvent {
+                                    scanner: Some(scanner.uuid),
+                                    message: ScannerMessage {
+                                        uuid: uuid::Uuid::new_v4(),
+                                        content: ScannerContent::Filter(filter),
+                                    },
+                                })
+                                .await;
                         }
                     }
+                }
+                shared::messages::scanner::ScannerContent::Ping(payload) => {
+                    self.send(ScannerEvent {
+                        scanner: event.scanner,
+                        message: ScannerMessage {
+                            uuid: uuid::Uuid::new_v4(),
+                            content: ScannerContent::Pong(payload),
+                        },
+                    })
+                    .await?;
+                }
+                shared::messages::scanner::ScannerContent::Pong(payload) => {
+                    if let Some(uuid) = event.scanner {
+                        self.pong(uuid, payload, None).await;
+                    }
+                }
+                shared::messages::scanner::ScannerContent::TimedPong { payload, time } => {
+                    if let Some(uuid) = event.scanner {
+                        self.pong(uuid, payload, Some(time)).await;
+                    }
+                }
+                shared::messages::scanner::ScannerContent::Ok(uuid) => {
+                    if self.pending.confirm(&uuid, event.scanner).is_some() {
+                        tracing::debug!("Message confirmed: {}", uuid);
+                    }
+                }
+                shared::messages::scanner::ScannerContent::Error(uuid, error) => {
+                    if let Some(pending) = self.pending.confirm(&uuid, event.scanner) {
+                        tracing::error!("Scanner rejected message: {:?} {}", pending.event, error);
+                        let context = self.context.read().await;
+                        let _ = context.web_broadcast.send(WebMessage::ScannerUnconfirmed(
+                            web::ScannerUnconfirmed {
+                                scanner: event.scanner.unwrap_or_default(),
+                                message: uuid,
+                                attempts: pending.attempts,
+                                error: Some(error),
+                            },
+                        ));
+                    }
+                }
+                shared::messages::scanner::ScannerContent::Status(status) => {
+                    if let Some(uuid) = event.scanner {
+                        let firmware = status.firmware.clone();
+                        {
+                            let mut context = self.context.write().await;
+                            let web_broadcast = context.web_broadcast.clone();
+                            if let Some(scanner) = context.database.data.scanners.get_mut(&uuid) {
+                                scanner.set_status(entities::ScannerStatus {
+                                    timestamp: self.now(),
+                                    status,
+                                });
+                                let _ =
+                                    web_broadcast.send(WebMessage::ScannerDetail(scanner.clone()));
+                            }
+                        }
 
-                    // Send position change
-                    if enabled {
-                        if context.database.activities.push(
-                            device_uuid,
-                            scanner_uuid,
-                            now,
-                            result.rssi.into(),
-                        ) {
-                            context
-                                .web_broadcast
-                                .send(crate::message::web::WebMessage::Activity(
-                                    crate::message::web::Activity {
-                                        device: device_uuid,
-                                        scanner: scanner_uuid,
-                                        rssi: result.rssi.into(),
-                                        timestamp: now,
-                                    },
-                                ));
+                        if let Some(progress) = self.updates.running(uuid, &firmware) {
+                            self.report_update(progress).await;
+                        }
+                    }
+                }
+                shared::messages::scanner::ScannerContent::UpdateStatus(status) => {
+                    if let Some(uuid) = event.scanner {
+                        if let Some(progress) = self.updates.status(uuid, status) {
+                            self.report_update(progress).await;
                         }
                     }
                 }
+                shared::messages::scanner::ScannerContent::ScanResult(result) => {
+                    let scanner_uuid = event.scanner.unwrap();
+                    let mut context = self.context.write().await;
+                    self.process_result(&mut context, scanner_uuid, result)
+                        .await;
+                }
+                shared::messages::scanner::ScannerContent::ScanResultBatch(results) => {
+                    let scanner_uuid = event.scanner.unwrap();
+                    // Whole batch is processed under one lock
+                    let mut context = self.context.write().await;
+                    for result in results {
+                        self.process_result(&mut context, scanner_uuid, result)
+                            .await;
+                    }
+                }
                 _ => {}
             }
         }
@@ -287,69 +1158,163 @@ impl Scanner {
         Ok(())
     }
 
-    pub async fn process_service<'a>(
+    // Round trip of answered ping, scanners with clock also refine their offset
+    async fn pong(&mut self, uuid: uuid::Uuid, payload: String, time: Option<u64>) {
+        let Some((_, sent)) = self.pings.remove(&uuid).filter(|ping| ping.0 == payload) else {
+            return;
+        };
+        let elapsed = sent.elapsed();
+        let received = self.now();
+
+        let mut context = self.context.write().await;
+        if let Some(scanner) = context.database.data.scanners.get_mut(&uuid) {
+            scanner.rtt = Some(elapsed.as_millis() as u64);
+            if let Some(time) = time {
+                let sent = received - chrono::Duration::from_std(elapsed).unwrap_or_default();
+                let sample = clock::sample(sent, received, time);
+                scanner.clock_offset = Some(clock::update(scanner.clock_offset, sample));
+            }
+        }
+    }
+
+    pub async fn process_result(
         &self,
-        context: &mut RwLockWriteGuard<'a, super::context::Context>,
+        context: &mut RwLockWriteGuard<'_, super::context::Context>,
+        scanner_uuid: uuid::Uuid,
+        result: ScanDevice,
+    ) {
+        let mut send_device = None;
+        let mut enabled = false;
+        // Scanner clock is used once its offset is known, result could wait in the batch
+        let received = self.now();
+        let offset = context
+            .database
+            .data
+            .scanners
+            .get(&scanner_uuid)
+            .and_then(|scanner| scanner.clock_offset);
+        let now = result
+            .timestamp
+            .zip(offset)
+            .and_then(|(time, offset)| clock::correct(offset, time, received))
+            .unwrap_or_else(|| received - chrono::Duration::milliseconds(result.age.into()));
+
+        // let activity_diff = context.database.config.base.activity_diff.clone();
+
+        let device_uuid = if let Some(device) = context
+            .database
+            .data
+            .devices
+            .values_mut()
+            .find(|d| d.mac == result.mac)
+        {
+            device.last_activity = now;
+            enabled = device.enabled;
+            device.uuid.clone()
+        } else {
+            let uuid = uuid::Uuid::new_v4();
+            let device = crate::database::entities::Device {
+                uuid,
+                name: None,
+                enabled: false,
+                mac: result.mac,
+                battery: None,
+                last_activity: now,
+                ..Default::default()
+            };
+            context.database.data.devices.insert(uuid, device.clone());
+            send_device = Some(device.clone());
+            device.uuid
+        };
+
+        if let Some(device) = context.database.data.devices.get(&device_uuid).cloned() {
+            if self
+                .process_service(context, scanner_uuid, device.uuid, result.data, now)
+                .await
+            {
+                enabled = device.enabled;
+                send_device = Some(device.clone());
+            }
+        }
+
+        if let Some(device) = send_device {
+            if device.name.is_some() {
+                context
+                    .web_broadcast
+                    .send(crate::message::web::WebMessage::DeviceDetail(device));
+            }
+        }
+
+        // Send position change
+        if enabled {
+            if context
+                .database
+                .activities
+                .push(device_uuid, scanner_uuid, now, result.rssi.into())
+            {
+                context
+                    .web_broadcast
+                    .send(crate::message::web::WebMessage::Activity(
+                        crate::message::web::Activity {
+                            device: device_uuid,
+                            scanner: scanner_uuid,
+                            rssi: result.rssi.into(),
+                            timestamp: now,
+                        },
+                    ));
+            }
+        }
+    }
+
+    pub async fn process_service(
+        &self,
+        context: &mut RwLockWriteGuard<'_, super::context::Context>,
         scanner: uuid::Uuid,
         device_uuid: uuid::Uuid,
         data: Vec<u8>,
+        timestamp: chrono::DateTime<chrono::Utc>,
     ) -> bool {
         let mut result = false;
         let mut events = Vec::new();
 
-        if let Some(device) = context.database.data.devices.get_mut(&device_uuid) {
+        let database = &mut context.database;
+        let base = &database.config.base;
+        if let Some(device) = database.data.devices.get_mut(&device_uuid) {
             tracing::debug!(
                 "Service advertisement data: {}: {:?}",
                 hex::encode(&device.mac),
                 data
             );
 
-            let mut parser = parser::Parser::new(data);
-            while let Some(parsed) = parser.next() {
-                //tracing::debug!("Service data[{}]: {:?}", parsed.tag, parsed.data);
-                match parsed.tag {
-                    // Short name or long name
-                    8 | 9 => {
-                        if device.name.is_none() {
-                            if let Ok(name) = String::from_utf8(parsed.data) {
-                                device.name = Some(name);
-                                result = true;
-                            }
-                        }
-                    }
-                    // Shelly beacon service
-                    22 => {
-                        if parsed.data[0..2] == [210, 252] {
-                            if device.battery != Some(parsed.data[6]) {
-                                device.battery = Some(parsed.data[6]);
-                                result = true;
-                            }
+            let readings = self
+                .decoders
+                .decode(base.decoders(&device.device_type), data);
 
-                            if parsed.data[8] > 0 && device.enabled {
-                                let kind = match parsed.data[8] {
-                                    1 => crate::database::entities::EventKind::ButtonPressed,
-                                    2 => crate::database::entities::EventKind::ButtonDoublePressed,
-                                    3 => crate::database::entities::EventKind::ButtonTriplePressed,
-                                    4 => crate::database::entities::EventKind::ButtonLongPressed,
-                                    254 => crate::database::entities::EventKind::ButtonHold,
-                                    _ => crate::database::entities::EventKind::Advertisement,
-                                };
-                                events.push(crate::database::entities::Event {
-                                    device: Some(device.uuid),
-                                    uuid: uuid::Uuid::new_v4(),
-                                    timestamp: chrono::offset::Utc::now(),
-                                    scanner: uuid::Uuid::new_v4(),
-                                    kind,
-                                });
-                            }
-                        }
-                    }
-                    // Unknown/UnImportant tag
-                    _ => {
-                        //tracing::info!("Unknow data[{}] {:?}", parsed.tag, parsed.data);
-                    }
+            if let Some(name) = readings.identity.filter(|_| device.name.is_none()) {
+                device.name = Some(name);
+                result = true;
+            }
+
+            if let Some(battery) = readings.battery.filter(|b| device.battery != Some(*b)) {
+                device.battery = Some(battery);
+                result = true;
+            }
+
+            for (kind, value) in readings.sensors {
+                if device.sensors.insert(kind, value) != Some(value) {
+                    result = true;
                 }
             }
+
+            if let Some(kind) = readings.button.filter(|_| device.enabled) {
+                events.push(crate::database::entities::Event {
+                    device: Some(device.uuid),
+                    uuid: uuid::Uuid::new_v4(),
+                    timestamp,
+                    scanner: uuid::Uuid::new_v4(),
+                    kind,
+                });
+            }
         }
 
         if let Some(activity) = context.database.activities.best(device_uuid) {
@@ -361,7 +1326,7 @@ impl Scanner {
                         && e.device == Some(device_uuid)
                         && e.kind == event.kind
                 }) {
-                    old_event.timestamp = event.timestamp;
+                    old_event.timestamp = old_event.timestamp.max(event.timestamp);
                     let event = old_event.clone();
                     context.web_broadcast.send(WebMessage::Event(event));
                 } else {
@@ -377,3 +1342,688 @@ impl Scanner {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::messages::scanner::{UpdateState, UpdateStatus};
+
+    // Scanner firmware simulated on a local socket, it speaks plain messages
+    struct Simulated {
+        socket: UdpSocket,
+    }
+
+    impl Simulated {
+        async fn recv(&self) -> (ScannerMessage, SocketAddr) {
+            let mut buf = [0u8; 2048];
+            let (len, addr) = self.socket.recv_from(&mut buf).await.unwrap();
+            let msg = ScannerWrapped::from_slice(&buf[..len])
+                .unwrap()
+                .open(None)
+                .unwrap();
+            (msg, addr)
+        }
+
+        async fn send(&self, addr: SocketAddr, content: ScannerContent) {
+            let msg = ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content,
+            };
+            self.socket
+                .send_to(&rmp_serde::to_vec(&msg).unwrap(), addr)
+                .await
+                .unwrap();
+        }
+    }
+
+    // Nothing reads scanner commands or streams, scanner under test sends them itself
+    fn context(database: crate::database::Database) -> crate::context::ContextWrapped {
+        let (scanner_sender, _) = tokio::sync::mpsc::channel(16);
+        let (scanner_stream, _) = tokio::sync::mpsc::channel(16);
+        std::sync::Arc::new(tokio::sync::RwLock::new(Context {
+            global_broadcast: broadcast::Sender::new(16),
+            web_broadcast: broadcast::Sender::new(64),
+            scanner_sender,
+            scanner_stream,
+            database,
+            alarms: BTreeMap::new(),
+        }))
+    }
+
+    fn progress(receiver: &mut broadcast::Receiver<WebMessage>) -> web::FirmwareProgress {
+        loop {
+            if let WebMessage::FirmwareProgress(progress) = receiver.try_recv().unwrap() {
+                return progress;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn firmware_rollout() {
+        let simulated = Simulated {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        };
+        let simulated_addr = simulated.socket.local_addr().unwrap();
+        let mac = vec![1, 2, 3, 4, 5, 6];
+        let capabilities = capability::ACK | capability::UPDATE;
+
+        let uuid = uuid::Uuid::new_v4();
+        let mut database = crate::database::Database::default();
+        database.data.scanners = BTreeMap::from_iter([(
+            uuid,
+            entities::Scanner {
+                uuid,
+                ip: simulated_addr.ip().to_string(),
+                port: simulated_addr.port(),
+                mac: mac.clone(),
+                protocol: PROTOCOL_VERSION,
+                firmware: String::from("1.0.0"),
+                capabilities,
+                ..Default::default()
+            },
+        )]);
+
+        let context = context(database);
+        let mut web_receiver = context.read().await.web_broadcast.subscribe();
+
+        let mut scanner = Scanner::new(context, vec![simulated_addr]).await;
+        // First call binds the socket
+        scanner
+            .recv(&["127.0.0.1:0".parse().unwrap()])
+            .await
+            .unwrap();
+
+        scanner
+            .send(ScannerEvent {
+                scanner: Some(uuid),
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content: ScannerContent::Update {
+                        url: String::from("http://127.0.0.1:3030/firmware/1.1.0.bin"),
+                        sha256: vec![0; 32],
+                        version: String::from("1.1.0"),
+                    },
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(progress(&mut web_receiver).state, UpdateState::Pending);
+        assert!(scanner.next_retry().is_some());
+
+        let (msg, server_addr) = simulated.recv().await;
+        assert!(matches!(msg.content, ScannerContent::Update { .. }));
+
+        simulated
+            .send(server_addr, ScannerContent::Ok(msg.uuid))
+            .await;
+        assert!(scanner.recv(&[server_addr]).await.unwrap());
+        assert!(scanner.next_retry().is_none());
+
+        simulated
+            .send(
+                server_addr,
+                ScannerContent::UpdateStatus(UpdateStatus {
+                    version: String::from("1.1.0"),
+                    state: UpdateState::Downloading,
+                    progress: 50,
+                }),
+            )
+            .await;
+        assert!(scanner.recv(&[server_addr]).await.unwrap());
+        let downloading = progress(&mut web_receiver);
+        assert_eq!(downloading.state, UpdateState::Downloading);
+        assert_eq!(downloading.progress, 50);
+
+        // Restarted scanner registers with the new firmware
+        simulated
+            .send(
+                server_addr,
+                ScannerContent::Register {
+                    mac,
+                    protocol: PROTOCOL_VERSION,
+                    firmware: String::from("1.1.0"),
+                    capabilities,
+                },
+            )
+            .await;
+        assert!(scanner.recv(&[server_addr]).await.unwrap());
+        let done = progress(&mut web_receiver);
+        assert_eq!(done.scanner, uuid);
+        assert_eq!(done.state, UpdateState::Done);
+    }
+
+    #[tokio::test]
+    async fn stream_transport() {
+        let mac = vec![6, 5, 4, 3, 2, 1];
+        let uuid = uuid::Uuid::new_v4();
+        let mut database = crate::database::Database::default();
+        // Stored address is not reachable, scanner sits behind NAT
+        database.data.scanners = BTreeMap::from_iter([(
+            uuid,
+            entities::Scanner {
+                uuid,
+                ip: String::from("192.168.0.10"),
+                port: 4242,
+                mac: mac.clone(),
+                protocol: PROTOCOL_VERSION,
+                capabilities: capability::LEGACY,
+                ..Default::default()
+            },
+        )]);
+
+        let context = context(database);
+
+        let mut scanner = Scanner::new(context.clone(), Vec::new()).await;
+        let register = ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::Register {
+                mac,
+                protocol: PROTOCOL_VERSION,
+                firmware: String::from("1.0.0"),
+                capabilities: capability::LEGACY,
+            },
+        };
+
+        // Connection without token can not take over the unkeyed scanner
+        let untrusted: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let (sender, _untrusted_receiver) = tokio::sync::mpsc::channel(16);
+        scanner
+            .stream(stream::StreamEvent::Open {
+                addr: untrusted,
+                sender,
+                trusted: false,
+            })
+            .await
+            .unwrap();
+        let received = scanner
+            .stream(stream::StreamEvent::Frame {
+                addr: untrusted,
+                data: rmp_serde::to_vec(&register).unwrap(),
+            })
+            .await
+            .unwrap();
+        assert!(!received);
+        assert_eq!(
+            context.read().await.database.data.scanners[&uuid].addr(),
+            Some("192.168.0.10:4242".parse().unwrap())
+        );
+
+        let addr: SocketAddr = "203.0.113.5:50123".parse().unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        scanner
+            .stream(stream::StreamEvent::Open {
+                addr,
+                sender,
+                trusted: true,
+            })
+            .await
+            .unwrap();
+        let received = scanner
+            .stream(stream::StreamEvent::Frame {
+                addr,
+                data: rmp_serde::to_vec(&register).unwrap(),
+            })
+            .await
+            .unwrap();
+        assert!(received);
+        assert_eq!(
+            context.read().await.database.data.scanners[&uuid].addr(),
+            Some(addr)
+        );
+
+        // Configuration goes back over the connection
+        let data = receiver.try_recv().unwrap();
+        let msg: ScannerMessage = rmp_serde::from_slice(&data).unwrap();
+        assert!(matches!(msg.content, ScannerContent::Set(_)));
+
+        scanner
+            .stream(stream::StreamEvent::Close { addr })
+            .await
+            .unwrap();
+        let sent = scanner
+            .send(ScannerEvent {
+                scanner: Some(uuid),
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content: ScannerContent::Hello,
+                },
+            })
+            .await
+            .unwrap();
+        assert!(!sent);
+    }
+
+    #[tokio::test]
+    async fn timed_ping() {
+        // Older firmware without CLOCK could not decode TimedPing
+        let scanners = [
+            ("203.0.113.5", capability::LEGACY | capability::PING),
+            (
+                "203.0.113.6",
+                capability::LEGACY | capability::PING | capability::CLOCK,
+            ),
+        ];
+        let mut database = crate::database::Database::default();
+        for (ip, capabilities) in scanners {
+            let uuid = uuid::Uuid::new_v4();
+            database.data.scanners.insert(
+                uuid,
+                entities::Scanner {
+                    uuid,
+                    ip: String::from(ip),
+                    port: 4242,
+                    protocol: PROTOCOL_VERSION,
+                    capabilities,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let context = context(database);
+
+        let mut scanner = Scanner::new(context, Vec::new()).await;
+        let mut receivers = Vec::new();
+        for (ip, _) in scanners {
+            let (sender, receiver) = tokio::sync::mpsc::channel(16);
+            let addr = SocketAddr::new(ip.parse().unwrap(), 4242);
+            scanner
+                .stream(stream::StreamEvent::Open {
+                    addr,
+                    sender,
+                    trusted: true,
+                })
+                .await
+                .unwrap();
+            receivers.push(receiver);
+        }
+
+        scanner.liveness().await;
+        let mut received = receivers.iter_mut().map(|receiver| {
+            let data = receiver.try_recv().unwrap();
+            rmp_serde::from_slice::<ScannerMessage>(&data)
+                .unwrap()
+                .content
+        });
+        assert!(matches!(received.next(), Some(ScannerContent::Ping(_))));
+        assert!(matches!(
+            received.next(),
+            Some(ScannerContent::TimedPing(_))
+        ));
+    }
+
+    // Scanner with one registered scanner and one enabled device on the recorded time
+    async fn replay_scanner() -> (
+        Scanner,
+        broadcast::Receiver<WebMessage>,
+        uuid::Uuid,
+        uuid::Uuid,
+    ) {
+        let scanner_uuid = uuid::Uuid::new_v4();
+        let device_uuid = uuid::Uuid::new_v4();
+        let mut database = crate::database::Database::default();
+        database.data.scanners = BTreeMap::from_iter([(
+            scanner_uuid,
+            entities::Scanner {
+                uuid: scanner_uuid,
+                ip: String::from("192.168.1.20"),
+                port: 3031,
+                mac: vec![1, 2, 3, 4, 5, 6],
+                protocol: PROTOCOL_VERSION,
+                capabilities: capability::LEGACY,
+                ..Default::default()
+            },
+        )]);
+        database.data.devices = BTreeMap::from_iter([(
+            device_uuid,
+            entities::Device {
+                uuid: device_uuid,
+                mac: vec![10, 11, 12, 13, 14, 15],
+                enabled: true,
+                ..Default::default()
+            },
+        )]);
+
+        let context = context(database);
+        let web_receiver = context.read().await.web_broadcast.subscribe();
+        (
+            Scanner::new(context, Vec::new()).await,
+            web_receiver,
+            scanner_uuid,
+            device_uuid,
+        )
+    }
+
+    // Activities and events sent to web, identifiers differ between databases
+    fn replayed(
+        web_receiver: &mut broadcast::Receiver<WebMessage>,
+        scanner_uuid: uuid::Uuid,
+        device_uuid: uuid::Uuid,
+    ) -> Vec<(i64, String, chrono::DateTime<chrono::Utc>)> {
+        let mut result = Vec::new();
+        while let Ok(msg) = web_receiver.try_recv() {
+            match msg {
+                WebMessage::Activity(activity) => {
+                    assert_eq!(activity.device, device_uuid);
+                    assert_eq!(activity.scanner, scanner_uuid);
+                    result.push((activity.rssi, String::from("activity"), activity.timestamp));
+                }
+                WebMessage::Event(event) => {
+                    assert_eq!(event.device, Some(device_uuid));
+                    assert_eq!(event.scanner, scanner_uuid);
+                    result.push((0, format!("{:?}", event.kind), event.timestamp));
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    async fn replay_capture(
+        records: &[capture::Record],
+    ) -> Vec<(i64, String, chrono::DateTime<chrono::Utc>)> {
+        let (mut scanner, mut web_receiver, scanner_uuid, device_uuid) = replay_scanner().await;
+        for record in records {
+            scanner.replay(record.clone()).await.unwrap();
+        }
+        replayed(&mut web_receiver, scanner_uuid, device_uuid)
+    }
+
+    #[tokio::test]
+    async fn replay_deterministic() {
+        let addr: SocketAddr = "192.168.1.20:3031".parse().unwrap();
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let scan = |ms: i64, rssi: i32, button: u8| capture::Record {
+            timestamp: start + chrono::Duration::milliseconds(ms),
+            addr,
+            message: ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content: ScannerContent::ScanResult(ScanDevice {
+                    mac: vec![10, 11, 12, 13, 14, 15],
+                    rssi,
+                    data: vec![
+                        2, 1, 6, 10, 22, 210, 252, 0x44, 0x00, 1, 0x01, 90, 0x3a, button,
+                    ],
+                    ..Default::default()
+                }),
+            },
+        };
+        let records = vec![
+            capture::Record {
+                timestamp: start,
+                addr,
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content: ScannerContent::Register {
+                        mac: vec![1, 2, 3, 4, 5, 6],
+                        protocol: PROTOCOL_VERSION,
+                        firmware: String::from("1.0.0"),
+                        capabilities: capability::LEGACY,
+                    },
+                },
+            },
+            scan(100, -60, 0),
+            scan(1_600, -70, 1),
+            scan(4_000, -65, 0),
+        ];
+
+        let first = replay_capture(&records).await;
+        let second = replay_capture(&records).await;
+        assert_eq!(first, second);
+
+        // Recorded time is used instead of the time of replay
+        assert!(first.contains(&(
+            -60,
+            String::from("activity"),
+            start + chrono::Duration::milliseconds(100)
+        )));
+        assert!(first.contains(&(
+            0,
+            String::from("ButtonPressed"),
+            start + chrono::Duration::milliseconds(1_600)
+        )));
+    }
+
+    #[tokio::test]
+    async fn scanner_clock() {
+        let addr: SocketAddr = "192.168.1.20:3031".parse().unwrap();
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let record = |ms: i64, content: ScannerContent| capture::Record {
+            timestamp: start + chrono::Duration::milliseconds(ms),
+            addr,
+            message: ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content,
+            },
+        };
+        let scan = |ms: i64, timestamp: Option<u64>| {
+            record(
+                ms,
+                ScannerContent::ScanResult(ScanDevice {
+                    mac: vec![10, 11, 12, 13, 14, 15],
+                    rssi: -60,
+                    data: vec![2, 1, 6, 10, 22, 210, 252, 0x44, 0x00, 1, 0x01, 90, 0x3a, 1],
+                    age: 500,
+                    timestamp,
+                }),
+            )
+        };
+
+        let (mut scanner, mut web_receiver, scanner_uuid, device_uuid) = replay_scanner().await;
+
+        // Offset is unknown, arrival minus age is used
+        scanner.replay(scan(1_000, Some(900))).await.unwrap();
+        let result = replayed(&mut web_receiver, scanner_uuid, device_uuid);
+        assert!(result.contains(&(
+            -60,
+            String::from("activity"),
+            start + chrono::Duration::milliseconds(500)
+        )));
+
+        // Scanner clock counts from boot, it was at 5 s when the ping was answered
+        scanner
+            .pings
+            .insert(scanner_uuid, (String::from("ping"), Instant::now()));
+        scanner
+            .replay(record(
+                2_000,
+                ScannerContent::TimedPong {
+                    payload: String::from("ping"),
+                    time: 5_000,
+                },
+            ))
+            .await
+            .unwrap();
+
+        // Result waited in a queue, scanner clock puts it 3 s after the pong
+        scanner.replay(scan(9_000, Some(8_000))).await.unwrap();
+        let result = replayed(&mut web_receiver, scanner_uuid, device_uuid);
+        let expected = start + chrono::Duration::milliseconds(5_000);
+        assert_eq!(result.len(), 2);
+        for (_, _, timestamp) in result {
+            assert!((timestamp - expected).num_milliseconds().abs() < 50);
+        }
+
+        // Restarted scanner registers again and its results fall back to age
+        scanner
+            .replay(record(
+                10_000,
+                ScannerContent::Register {
+                    mac: vec![1, 2, 3, 4, 5, 6],
+                    protocol: PROTOCOL_VERSION,
+                    firmware: String::from("1.0.0"),
+                    capabilities: capability::LEGACY | capability::CLOCK,
+                },
+            ))
+            .await
+            .unwrap();
+        scanner.replay(scan(11_000, Some(100))).await.unwrap();
+        let result = replayed(&mut web_receiver, scanner_uuid, device_uuid);
+        assert!(result.contains(&(
+            -60,
+            String::from("activity"),
+            start + chrono::Duration::milliseconds(10_500)
+        )));
+    }
+
+    #[tokio::test]
+    async fn keyed_scanner_signs() {
+        let (scanner, _web_receiver, scanner_uuid, _) = replay_scanner().await;
+        let key = vec![7u8; 32];
+        scanner
+            .context
+            .write()
+            .await
+            .database
+            .data
+            .scanners
+            .get_mut(&scanner_uuid)
+            .unwrap()
+            .key = Some(hex::encode(&key));
+
+        let addr: SocketAddr = "192.168.1.20:3031".parse().unwrap();
+        let msg = ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::Ping(String::from("ping")),
+        };
+
+        // Mixed security accepts plain messages only from scanners without key
+        let plain = ScannerWrapped::plain(&msg).unwrap().to_vec().unwrap();
+        assert!(scanner.open(&addr, &plain).await.is_err());
+        let signed = ScannerWrapped::hashed(&msg, &key)
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let (opened, origin) = scanner.open(&addr, &signed).await.unwrap();
+        assert_eq!(opened, msg);
+        assert_eq!(origin.unwrap().scanner, scanner_uuid);
+    }
+
+    #[tokio::test]
+    async fn multicast_interface() {
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        let sock = socket2::SockRef::from(&socket);
+
+        // Group on loopback, index 1, then the default interface
+        Scanner::multicast_if(&socket, "[ff02::1%1]:4242".parse().unwrap());
+        assert_eq!(sock.multicast_if_v6().unwrap(), 1);
+        Scanner::multicast_if(&socket, "[ff02::1]:4242".parse().unwrap());
+        assert_eq!(sock.multicast_if_v6().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn bind_retry() {
+        let (mut scanner, _web_receiver, _, _) = replay_scanner().await;
+        let busy = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let taken = busy.local_addr().unwrap();
+        let free = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        scanner.bind(&[free, taken]).await;
+        assert_eq!(scanner.sockets.len(), 1);
+        assert_eq!(scanner.unbound, vec![taken]);
+
+        // Only the failed address is bound again
+        drop(busy);
+        scanner.bind(&[free, taken]).await;
+        assert_eq!(scanner.sockets.len(), 2);
+        assert!(scanner.unbound.is_empty());
+    }
+
+    #[tokio::test]
+    async fn saved_sequence() {
+        let (mut scanner, _web_receiver, scanner_uuid, _) = replay_scanner().await;
+        let key = vec![7u8; 32];
+        {
+            let mut context = scanner.context.write().await;
+            let saved = context
+                .database
+                .data
+                .scanners
+                .get_mut(&scanner_uuid)
+                .unwrap();
+            saved.key = Some(hex::encode(&key));
+        }
+        scanner.sequences.insert(scanner_uuid, 100);
+
+        let addr: SocketAddr = "192.168.1.20:3031".parse().unwrap();
+        let signed = |sequence: u64| {
+            let msg = ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content: ScannerContent::Ping(String::from("ping")),
+            };
+            ScannerWrapped::sequenced(&msg, &key, sequence)
+                .unwrap()
+                .to_vec()
+                .unwrap()
+        };
+
+        // Messages captured before restart are rejected, later ones are accepted
+        assert!(!scanner.receive(addr, signed(100)).await.unwrap());
+        assert!(!scanner.receive(addr, signed(99)).await.unwrap());
+        assert!(scanner.receive(addr, signed(101)).await.unwrap());
+        assert_eq!(
+            scanner.replay.tops().collect::<Vec<_>>(),
+            vec![(scanner_uuid, 101)]
+        );
+
+        // Sequence file is written only when a sequence moved
+        assert!(scanner.update_sequences());
+        assert!(!scanner.update_sequences());
+        assert_eq!(scanner.sequences[&scanner_uuid], 101);
+    }
+
+    #[tokio::test]
+    async fn pending_enrollment() {
+        let (mut scanner, _web_receiver, _, _) = replay_scanner().await;
+        {
+            let mut context = scanner.context.write().await;
+            let base = &mut context.database.config.base;
+            base.scanner_security = ScannerSecurity::Hashed;
+            base.scanner_pending_limit = 3;
+        }
+        let register = |mac: u8| ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::Register {
+                mac: vec![9, 9, 9, 9, 9, mac],
+                protocol: PROTOCOL_VERSION,
+                firmware: String::from("1.0.0"),
+                capabilities: capability::LEGACY,
+            },
+        };
+        let addr: SocketAddr = "192.168.1.30:3031".parse().unwrap();
+
+        // Unknown scanner enrolls with plain Register, adopted one must sign
+        let plain = ScannerWrapped::plain(&register(1))
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        assert!(scanner.open(&addr, &plain).await.is_ok());
+        let mut adopted = register(0);
+        adopted.content = ScannerContent::Register {
+            mac: vec![1, 2, 3, 4, 5, 6],
+            protocol: PROTOCOL_VERSION,
+            firmware: String::from("1.0.0"),
+            capabilities: capability::LEGACY,
+        };
+        let plain = ScannerWrapped::plain(&adopted).unwrap().to_vec().unwrap();
+        assert!(scanner.open(&addr, &plain).await.is_err());
+
+        // Flood of new macs keeps only the most recent ones
+        for mac in 1..=10 {
+            scanner.clock = Some(chrono::DateTime::from_timestamp(mac as i64, 0).unwrap());
+            assert!(scanner.get_event(&addr, register(mac)).await.is_none());
+        }
+        let context = scanner.context.read().await;
+        let mut macs: Vec<u8> = context
+            .database
+            .data
+            .pending_scanners
+            .values()
+            .map(|s| s.mac[5])
+            .collect();
+        macs.sort();
+        assert_eq!(macs, vec![8, 9, 10]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use shared::messages::scanner::{ScannerContent, ScannerEvent};
+
+pub struct Pending {
+    pub event: ScannerEvent,
+    pub attempts: u32,
+    pub next: Instant,
+}
+
+// Commands waiting for Ok from scanner, keyed by message uuid
+pub struct PendingMap {
+    inner: BTreeMap<uuid::Uuid, Pending>,
+}
+
+impl PendingMap {
+    pub fn new() -> Self {
+        PendingMap {
+            inner: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_tracked(content: &ScannerContent) -> bool {
+        matches!(
+            content,
+            ScannerContent::Set(..)
+                | ScannerContent::Restart
+                | ScannerContent::Identify(..)
+                | ScannerContent::Update { .. }
+                | ScannerContent::Filter(..)
+        )
+    }
+
+    // Restart is never repeated, a lost Ok would reboot the scanner again
+    fn is_resent(content: &ScannerContent) -> bool {
+        !matches!(content, ScannerContent::Restart)
+    }
+
+    // Parts of one allow-list are confirmed each on its own
+    fn supersedes(content: &ScannerContent, older: &ScannerContent) -> bool {
+        match (content, older) {
+            (ScannerContent::Filter(filter), ScannerContent::Filter(older)) => {
+                match (&filter.part, &older.part) {
+                    (Some(part), Some(older)) => {
+                        part.revision != older.revision || part.index == older.index
+                    }
+                    _ => true,
+                }
+            }
+            _ => std::mem::discriminant(content) == std::mem::discriminant(older),
+        }
+    }
+
+    pub fn push(&mut self, event: ScannerEvent, timeout: Duration) {
+        // Newer command replaces the older one, retransmission must not revert the state
+        self.inner.retain(|_, p| {
+            p.event.scanner != event.scanner
+                || !Self::supersedes(&event.message.content, &p.event.message.content)
+        });
+
+        self.inner.insert(
+            event.message.uuid,
+            Pending {
+                event,
+                attempts: 1,
+                next: Instant::now() + timeout,
+            },
+        );
+    }
+
+    // Only the addressed scanner can confirm the command
+    pub fn confirm(&mut self, uuid: &uuid::Uuid, scanner: Option<uuid::Uuid>) -> Option<Pending> {
+        if self.inner.get(uuid)?.event.scanner == scanner {
+            self.inner.remove(uuid)
+        } else {
+            None
+        }
+    }
+
+    // Scanner registering again has restarted
+    pub fn registered(&mut self, scanner: uuid::Uuid) {
+        self.inner.retain(|_, p| {
+            p.event.scanner != Some(scanner) || p.event.message.content != ScannerContent::Restart
+        });
+    }
+
+    pub fn next(&self) -> Option<Instant> {
+        self.inner.values().map(|p| p.next).min()
+    }
+
+    // Pop commands which run out of attempts, schedule the others with exponential backoff
+    pub fn due(
+        &mut self,
+        timeout: Duration,
+        retries: u32,
+    ) -> (Vec<ScannerEvent>, Vec<(ScannerEvent, u32)>) {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let mut failed = Vec::new();
+
+        self.inner.retain(|_, p| {
+            if p.next > now {
+                return true;
+            }
+
+            if p.attempts > retries {
+                failed.push((p.event.clone(), p.attempts));
+                return false;
+            }
+
+            p.next = now + timeout * 2u32.saturating_pow(p.attempts);
+            p.attempts += 1;
+            if Self::is_resent(&p.event.message.content) {
+                resend.push(p.event.clone());
+            }
+            true
+        });
+
+        (resend, failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::messages::scanner::ScannerMessage;
+
+    fn event(scanner: uuid::Uuid, content: ScannerContent) -> ScannerEvent {
+        ScannerEvent {
+            scanner: Some(scanner),
+            message: ScannerMessage {
+                uuid: uuid::Uuid::new_v4(),
+                content,
+            },
+        }
+    }
+
+    #[test]
+    fn restart_confirmed_by_register() {
+        let scanner = uuid::Uuid::new_v4();
+        let mut pending = PendingMap::new();
+        pending.push(event(scanner, ScannerContent::Restart), Duration::ZERO);
+        pending.push(event(scanner, ScannerContent::Identify(5)), Duration::ZERO);
+
+        // Identify is retransmitted, Restart only waits
+        let (resend, failed) = pending.due(Duration::ZERO, 3);
+        assert_eq!(resend.len(), 1);
+        assert_eq!(resend[0].message.content, ScannerContent::Identify(5));
+        assert!(failed.is_empty());
+
+        pending.registered(scanner);
+        let (resend, _) = pending.due(Duration::ZERO, 3);
+        assert_eq!(resend.len(), 1);
+
+        // Scanner which never comes back is reported
+        pending.push(event(scanner, ScannerContent::Restart), Duration::ZERO);
+        let failed: Vec<ScannerEvent> = (0..3)
+            .flat_map(|_| pending.due(Duration::ZERO, 1).1)
+            .map(|(event, _)| event)
+            .collect();
+        assert!(failed
+            .iter()
+            .any(|event| event.message.content == ScannerContent::Restart));
+    }
+
+    #[test]
+    fn filter_parts_pending() {
+        let scanner = uuid::Uuid::new_v4();
+        let filter = shared::messages::scanner::Filter {
+            macs: (0..100u8).map(|i| vec![1, 2, 3, 4, 5, i]).collect(),
+            ..Default::default()
+        };
+        let mut pending = PendingMap::new();
+        let push = |pending: &mut PendingMap, revision| {
+            for part in filter.clone().split(revision, |part| part.macs.len() <= 10) {
+                pending.push(event(scanner, ScannerContent::Filter(part)), Duration::ZERO);
+            }
+        };
+
+        // Every part waits for its Ok, newer revision replaces all of them
+        push(&mut pending, 1);
+        let parts = pending.inner.len();
+        assert!(parts > 1);
+        push(&mut pending, 2);
+        assert_eq!(pending.inner.len(), parts);
+        assert!(pending.inner.values().all(|p| matches!(
+            &p.event.message.content,
+            ScannerContent::Filter(filter) if filter.part.as_ref().unwrap().revision == 2
+        )));
+    }
+}
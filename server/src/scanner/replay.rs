@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+
+// Number of sequences behind the newest one which may still arrive out of order
+pub const WINDOW: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    Duplicate,
+    Stale,
+    Unsequenced,
+}
+
+impl std::fmt::Display for Replay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Replay::Duplicate => write!(f, "Duplicate scanner message"),
+            Replay::Stale => write!(f, "Stale scanner message"),
+            Replay::Unsequenced => write!(f, "Scanner message without sequence"),
+        }
+    }
+}
+
+// Newest accepted sequence and bitmap of accepted ones behind it
+#[derive(Debug, Default)]
+pub struct Window {
+    top: u64,
+    seen: u64,
+}
+
+impl Window {
+    // Every sequence up to the saved one counts as seen
+    fn restored(top: u64) -> Self {
+        Window {
+            top,
+            seen: u64::MAX,
+        }
+    }
+
+    pub fn check(&mut self, sequence: u64) -> Result<(), Replay> {
+        if sequence > self.top {
+            let shift = sequence - self.top;
+            self.seen = if shift >= WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.top = sequence;
+            return Ok(());
+        }
+
+        let offset = self.top - sequence;
+        if offset >= WINDOW {
+            return Err(Replay::Stale);
+        }
+
+        let bit = 1 << offset;
+        if self.seen & bit != 0 {
+            return Err(Replay::Duplicate);
+        }
+        self.seen |= bit;
+        Ok(())
+    }
+}
+
+pub struct ReplayMap {
+    inner: BTreeMap<uuid::Uuid, Window>,
+}
+
+impl ReplayMap {
+    pub fn new() -> Self {
+        ReplayMap {
+            inner: BTreeMap::new(),
+        }
+    }
+
+    // Once scanner sent a sequence, it must not fall back to messages without it,
+    // saved sequence from before restart rejects the messages captured until then
+    pub fn check(
+        &mut self,
+        scanner: uuid::Uuid,
+        sequence: u64,
+        required: bool,
+        saved: u64,
+    ) -> Result<(), Replay> {
+        if sequence == 0 {
+            if required || saved != 0 || self.inner.contains_key(&scanner) {
+                return Err(Replay::Unsequenced);
+            }
+            return Ok(());
+        }
+
+        self.inner
+            .entry(scanner)
+            .or_insert_with(|| Window::restored(saved))
+            .check(sequence)
+    }
+
+    // Newest accepted sequence per scanner
+    pub fn tops(&self) -> impl Iterator<Item = (uuid::Uuid, u64)> + '_ {
+        self.inner
+            .iter()
+            .map(|(scanner, window)| (*scanner, window.top))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut window = Window::default();
+
+        assert_eq!(window.check(10), Ok(()));
+        assert_eq!(window.check(10), Err(Replay::Duplicate));
+        // Out of order inside the window
+        assert_eq!(window.check(8), Ok(()));
+        assert_eq!(window.check(8), Err(Replay::Duplicate));
+        assert_eq!(window.check(12), Ok(()));
+        assert_eq!(window.check(10), Err(Replay::Duplicate));
+        assert_eq!(window.check(11), Ok(()));
+
+        assert_eq!(window.check(12 + WINDOW), Ok(()));
+        assert_eq!(window.check(12), Err(Replay::Stale));
+        assert_eq!(window.check(13), Ok(()));
+    }
+
+    #[test]
+    fn unsequenced() {
+        let scanner = uuid::Uuid::new_v4();
+        let mut replay = ReplayMap::new();
+
+        assert_eq!(replay.check(scanner, 0, false, 0), Ok(()));
+        assert_eq!(replay.check(scanner, 0, true, 0), Err(Replay::Unsequenced));
+
+        assert_eq!(replay.check(scanner, 1, false, 0), Ok(()));
+        assert_eq!(replay.check(scanner, 0, false, 0), Err(Replay::Unsequenced));
+        assert_eq!(replay.check(scanner, 1, false, 0), Err(Replay::Duplicate));
+    }
+
+    #[test]
+    fn restored() {
+        let scanner = uuid::Uuid::new_v4();
+        let mut replay = ReplayMap::new();
+
+        // Sequence saved before restart, older messages are rejected even inside the window
+        assert_eq!(
+            replay.check(scanner, 0, false, 100),
+            Err(Replay::Unsequenced)
+        );
+        assert_eq!(
+            replay.check(scanner, 99, false, 100),
+            Err(Replay::Duplicate)
+        );
+        assert_eq!(
+            replay.check(scanner, 100, false, 100),
+            Err(Replay::Duplicate)
+        );
+        assert_eq!(replay.check(scanner, 101, false, 100), Ok(()));
+        assert_eq!(replay.tops().collect::<Vec<_>>(), vec![(scanner, 101)]);
+    }
+}
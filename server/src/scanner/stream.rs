@@ -0,0 +1,62 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use tokio::sync::mpsc;
+
+// Scanner connected over WebSocket, frames carry the same data as UDP datagrams
+#[derive(Debug)]
+pub enum StreamEvent {
+    // Unsigned frames are accepted only from trusted connections
+    Open {
+        addr: SocketAddr,
+        sender: mpsc::Sender<Vec<u8>>,
+        trusted: bool,
+    },
+    Frame {
+        addr: SocketAddr,
+        data: Vec<u8>,
+    },
+    Close {
+        addr: SocketAddr,
+    },
+}
+
+// Open connections by peer address, scanners behind NAT are reachable only this way
+#[derive(Default)]
+pub struct StreamMap {
+    inner: BTreeMap<SocketAddr, (mpsc::Sender<Vec<u8>>, bool)>,
+}
+
+impl StreamMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, addr: SocketAddr, sender: mpsc::Sender<Vec<u8>>, trusted: bool) {
+        self.inner.insert(addr, (sender, trusted));
+    }
+
+    pub fn close(&mut self, addr: &SocketAddr) {
+        self.inner.remove(addr);
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.inner.contains_key(addr)
+    }
+
+    // Connection which may send unsigned frames
+    pub fn is_trusted(&self, addr: &SocketAddr) -> bool {
+        self.inner.get(addr).is_some_and(|(_, trusted)| *trusted)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    // Slow connection drops the frame instead of blocking other scanners
+    pub fn send(&self, addr: &SocketAddr, data: Vec<u8>) -> bool {
+        match self.inner.get(addr) {
+            Some((sender, _)) => sender.try_send(data).is_ok(),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use shared::messages::scanner::{UpdateState, UpdateStatus};
+
+use crate::message::web::FirmwareProgress;
+
+pub struct Update {
+    pub version: String,
+    pub state: UpdateState,
+    pub progress: u8,
+    pub changed: Instant,
+}
+
+// Running firmware rollouts keyed by scanner uuid
+pub struct UpdateMap {
+    inner: BTreeMap<uuid::Uuid, Update>,
+}
+
+impl UpdateMap {
+    pub fn new() -> Self {
+        UpdateMap {
+            inner: BTreeMap::new(),
+        }
+    }
+
+    fn progress(scanner: uuid::Uuid, update: &Update) -> FirmwareProgress {
+        FirmwareProgress {
+            scanner,
+            version: update.version.clone(),
+            state: update.state.clone(),
+            progress: update.progress,
+        }
+    }
+
+    // Newer rollout replaces the running one
+    pub fn start(&mut self, scanner: uuid::Uuid, version: String) -> FirmwareProgress {
+        let update = Update {
+            version,
+            state: UpdateState::Pending,
+            progress: 0,
+            changed: Instant::now(),
+        };
+        let progress = Self::progress(scanner, &update);
+        self.inner.insert(scanner, update);
+        progress
+    }
+
+    // Progress reported by scanner, Done is decided by the firmware it runs after restart
+    pub fn status(
+        &mut self,
+        scanner: uuid::Uuid,
+        status: UpdateStatus,
+    ) -> Option<FirmwareProgress> {
+        let update = self.inner.get_mut(&scanner)?;
+        if update.version != status.version || status.state == UpdateState::Done {
+            return None;
+        }
+
+        update.state = status.state;
+        update.progress = status.progress.min(100);
+        update.changed = Instant::now();
+        let progress = Self::progress(scanner, update);
+
+        if let UpdateState::Failed(..) = progress.state {
+            self.inner.remove(&scanner);
+        }
+        Some(progress)
+    }
+
+    // Scanner announced its firmware in Register or Status
+    pub fn running(&mut self, scanner: uuid::Uuid, firmware: &str) -> Option<FirmwareProgress> {
+        if self.inner.get(&scanner)?.version != firmware {
+            return None;
+        }
+
+        let mut update = self.inner.remove(&scanner)?;
+        update.state = UpdateState::Done;
+        update.progress = 100;
+        Some(Self::progress(scanner, &update))
+    }
+
+    pub fn fail(&mut self, scanner: uuid::Uuid, error: &str) -> Option<FirmwareProgress> {
+        let mut update = self.inner.remove(&scanner)?;
+        update.state = UpdateState::Failed(error.to_string());
+        Some(Self::progress(scanner, &update))
+    }
+
+    // Pop rollouts without any progress for timeout
+    pub fn expired(&mut self, timeout: Duration) -> Vec<FirmwareProgress> {
+        let expired: Vec<uuid::Uuid> = self
+            .inner
+            .iter()
+            .filter(|(_, update)| update.changed.elapsed() >= timeout)
+            .map(|(scanner, _)| *scanner)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|scanner| self.fail(scanner, "Update timed out"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(version: &str, state: UpdateState, progress: u8) -> UpdateStatus {
+        UpdateStatus {
+            version: version.into(),
+            state,
+            progress,
+        }
+    }
+
+    #[test]
+    fn rollout_done_after_restart() {
+        let scanner = uuid::Uuid::new_v4();
+        let mut updates = UpdateMap::new();
+
+        assert_eq!(
+            updates.start(scanner, "1.1.0".into()).state,
+            UpdateState::Pending
+        );
+
+        let progress = updates
+            .status(scanner, status("1.1.0", UpdateState::Downloading, 40))
+            .unwrap();
+        assert_eq!(progress.state, UpdateState::Downloading);
+        assert_eq!(progress.progress, 40);
+
+        // Other version and self reported Done are ignored
+        assert!(updates
+            .status(scanner, status("1.0.0", UpdateState::Installing, 100))
+            .is_none());
+        assert!(updates
+            .status(scanner, status("1.1.0", UpdateState::Done, 100))
+            .is_none());
+
+        assert!(updates.running(scanner, "1.0.0").is_none());
+        assert_eq!(
+            updates.running(scanner, "1.1.0").unwrap().state,
+            UpdateState::Done
+        );
+        assert!(updates.running(scanner, "1.1.0").is_none());
+    }
+
+    #[test]
+    fn rollout_failures() {
+        let scanner = uuid::Uuid::new_v4();
+        let mut updates = UpdateMap::new();
+
+        updates.start(scanner, "1.1.0".into());
+        let failed = UpdateState::Failed("Checksum".into());
+        assert_eq!(
+            updates
+                .status(scanner, status("1.1.0", failed.clone(), 100))
+                .unwrap()
+                .state,
+            failed
+        );
+        assert!(updates.fail(scanner, "Again").is_none());
+
+        updates.start(scanner, "1.1.0".into());
+        assert!(updates.expired(Duration::from_secs(60)).is_empty());
+        let expired = updates.expired(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(
+            expired[0].state,
+            UpdateState::Failed("Update timed out".into())
+        );
+    }
+}
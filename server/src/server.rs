@@ -23,13 +23,13 @@ pub struct Server {
 }
 
 impl Server {
-    pub fn new(
+    pub async fn new(
         context: ContextWrapped,
-        broadcast: SocketAddr,
+        broadcast: Vec<SocketAddr>,
         global_sender: tokio::sync::broadcast::Sender<GlobalMessage>,
     ) -> Self {
         Self {
-            scanner: crate::scanner::Scanner::new(context.clone(), broadcast),
+            scanner: crate::scanner::Scanner::new(context.clone(), broadcast).await,
             context,
             global_sender,
         }
@@ -38,20 +38,35 @@ impl Server {
     pub async fn run(
         &mut self,
         mut scanner_receiver: tokio::sync::mpsc::Receiver<ScannerEvent>,
+        mut stream_receiver: tokio::sync::mpsc::Receiver<crate::scanner::stream::StreamEvent>,
     ) -> anyhow::Result<()> {
         'main: loop {
             tracing::debug!("Starting server...");
 
             let mut web = super::web::Server::new(self.context.clone());
             let web_future: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-                let _ = web.run().await;
+                if let Err(err) = web.run().await {
+                    tracing::error!("WebServer stopped: {}", err);
+                }
             });
 
+            // Building management bridge runs next to the web server
+            let mqtt_future = if self.context.read().await.database.config.mqtt.enabled {
+                let mut bridge = crate::mqtt::Bridge::new(self.context.clone());
+                Some(tokio::spawn(async move {
+                    if let Err(err) = bridge.run().await {
+                        tracing::error!("MQTT bridge stopped: {}", err);
+                    }
+                }))
+            } else {
+                None
+            };
+
             let mut global_receiver = self.global_sender.subscribe();
             let scanner_sender = self.context.read().await.scanner_sender.clone();
 
             let base = self.context.read().await.database.config.base.clone();
-            let (port, broadcast) = (base.port_scanner.clone(), base.port_broadcast.clone());
+            let ports = base.port_scanner.clone();
 
             let sleep_time = std::time::Duration::from_secs(5);
             let mut sleep = Instant::now() + sleep_time;
@@ -59,8 +74,16 @@ impl Server {
             loop {
                 //tracing::info!("Server loop cycle... {:?}", sleep.elapsed().is_zero());
 
+                // Wake up for retransmission of unconfirmed scanner commands
+                let timeout = self
+                    .scanner
+                    .next_retry()
+                    .map(|next| next.saturating_duration_since(Instant::now()))
+                    .unwrap_or(sleep_time)
+                    .min(sleep_time);
+
                 tokio::select! {
-                    _ = tokio::time::sleep(sleep_time) => {
+                    _ = tokio::time::sleep(timeout) => {
 
                     },
                     // Received system message for scanner/ resend to devices
@@ -70,10 +93,17 @@ impl Server {
 
                     }
 
-                    _ = self.scanner.recv(SocketAddr::V4(port)) => {
+                    _ = self.scanner.recv(&ports) => {
                         //tracing::info!("Recv cycle");
                     }
 
+                    // Scanners connected over WebSocket
+                    Some(event) = stream_receiver.recv() => {
+                        if let Err(err) = self.scanner.stream(event).await {
+                            tracing::error!("{}", err);
+                        }
+                    }
+
                     Ok(msg) = global_receiver.recv() => {
                         tracing::info!("Global cycle");
                         match msg {
@@ -84,6 +114,9 @@ impl Server {
 
                             shared::messages::global::GlobalMessage::Stop => {
                                 tracing::info!("Stopping server");
+                                if let Err(err) = self.scanner.save_sequences().await {
+                                    tracing::error!("Unable to save scanner sequences: {}", err);
+                                }
                                 break 'main;
                             }
                             _ => {
@@ -94,14 +127,20 @@ impl Server {
                     }
                 }
 
+                self.scanner.retry().await;
+
                 if !sleep.elapsed().is_zero() {
                     sleep = sleep + sleep_time;
                     Self::scanner_routine(&scanner_sender).await;
+                    self.scanner.liveness().await;
                     Self::web_routine(self.context.clone()).await;
                 }
             }
 
             web_future.await?;
+            if let Some(mqtt_future) = mqtt_future {
+                mqtt_future.await?;
+            }
         }
 
         Ok(())
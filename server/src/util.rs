@@ -1,5 +1,6 @@
 use anyhow::Context;
 use chrono::{DateTime, Utc};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 
 pub fn now() -> DateTime<Utc> {
     chrono::offset::Utc::now()
@@ -33,3 +34,59 @@ where
     let json = serde_json::to_value(value)?;
     Ok(serde_json::to_writer_pretty(file, &json)?)
 }
+
+// Dual-stack sockets report IPv4 peers as mapped IPv6 addresses
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+// Address as stored with scanners, link-local IPv6 keeps its zone like fe80::1%2
+pub fn format_ip(addr: &SocketAddr) -> String {
+    match canonical_addr(*addr) {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 => format!("{}%{}", v6.ip(), v6.scope_id()),
+        addr => addr.ip().to_string(),
+    }
+}
+
+pub fn parse_addr(ip: &str, port: u16) -> Option<SocketAddr> {
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+    let addr = match ip.split_once('%') {
+        Some((ip, zone)) => SocketAddr::V6(SocketAddrV6::new(
+            ip.parse().ok()?,
+            port,
+            0,
+            zone.parse().ok()?,
+        )),
+        None => SocketAddr::new(ip.parse().ok()?, port),
+    };
+    Some(canonical_addr(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanner_addresses() {
+        let v4: SocketAddr = "192.168.1.10:4242".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.168.1.10]:4242".parse().unwrap();
+        assert_eq!(canonical_addr(mapped), v4);
+        assert_eq!(format_ip(&mapped), "192.168.1.10");
+        assert_eq!(parse_addr("192.168.1.10", 4242), Some(v4));
+
+        let link: SocketAddr = "[fe80::1%3]:4242".parse().unwrap();
+        assert_eq!(format_ip(&link), "fe80::1%3");
+        assert_eq!(parse_addr("fe80::1%3", 4242), Some(link));
+        assert_eq!(
+            parse_addr("[2001:db8::5]", 4242),
+            Some("[2001:db8::5]:4242".parse().unwrap())
+        );
+        assert_eq!(parse_addr("scanner.local", 4242), None);
+    }
+}
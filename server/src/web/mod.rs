@@ -9,6 +9,7 @@ pub use operator::Operator;
 use crate::{
     database::entities::Role,
     message::web::{Version, WebMessage},
+    scanner::stream::StreamEvent,
 };
 
 type Result<T> = std::result::Result<T, Rejection>;
@@ -43,7 +44,8 @@ impl Server {
     fn encode(
         msg: &crate::message::web::WebMessage,
     ) -> std::result::Result<warp::ws::Message, String> {
-        if let Ok(value) = serde_json::to_string(msg) {
+        let redacted = msg.redacted();
+        if let Ok(value) = serde_json::to_string(redacted.as_ref().unwrap_or(msg)) {
             Ok(warp::ws::Message::text(value))
         } else {
             Err(String::from("Unable encode message"))
@@ -161,6 +163,107 @@ impl Server {
         Ok(())
     }
 
+    async fn scanner_handler(
+        ws: warp::ws::Ws,
+        addr: Option<std::net::SocketAddr>,
+        query: ScannerQuery,
+        context: super::context::ContextWrapped,
+    ) -> Result<impl warp::Reply> {
+        let trusted = query.trusted(&context.read().await.database.config.base);
+        Ok(ws.on_upgrade(move |socket| async move {
+            let Some(addr) = addr else {
+                tracing::error!("Scanner connection without remote address");
+                return;
+            };
+            if let Err(err) = Server::scanner_connection(socket, addr, trusted, context).await {
+                tracing::error!("{:?}", err);
+            }
+        }))
+    }
+
+    // Scanners behind NAT keep connection open and get the same frames as over UDP
+    async fn scanner_connection(
+        ws: warp::ws::WebSocket,
+        addr: std::net::SocketAddr,
+        trusted: bool,
+        context: super::context::ContextWrapped,
+    ) -> anyhow::Result<()> {
+        let addr = crate::util::canonical_addr(addr);
+        tracing::debug!("Scanner connected: {}", addr);
+
+        let (mut ws_sender, mut ws_receiver) = ws.split();
+        let (mut global_receiver, stream, query_size) = {
+            let context = context.read().await;
+            (
+                context.global_broadcast.subscribe(),
+                context.scanner_stream.clone(),
+                context.database.config.base.query_size,
+            )
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Vec<u8>>(query_size);
+
+        stream
+            .send(StreamEvent::Open {
+                addr,
+                sender,
+                trusted,
+            })
+            .await?;
+
+        loop {
+            tokio::select! {
+                msg = global_receiver.recv() => {
+                    match msg {
+                        Ok(shared::messages::global::GlobalMessage::Reload) => {
+                            tracing::info!("Reloading scanner WebSocket");
+                        }
+                        _ => {
+                            tracing::info!("Stopping scanner WebSocket");
+                            break;
+                        }
+                    }
+                }
+
+                // Frames from scanner, text and pings are ignored
+                msg = ws_receiver.next() => {
+                    match msg {
+                        Some(Ok(ws_msg)) if ws_msg.is_binary() => {
+                            stream
+                                .send(StreamEvent::Frame { addr, data: ws_msg.into_bytes() })
+                                .await?;
+                        }
+                        Some(Ok(ws_msg)) if !ws_msg.is_close() => {}
+                        _ => break,
+                    }
+                }
+
+                // Frames to scanner
+                Some(data) = receiver.recv() => {
+                    if let Err(err) = ws_sender.send(warp::ws::Message::binary(data)).await {
+                        tracing::error!("Unable to send message to scanner {}: {}", addr, err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        tracing::debug!("Scanner disconnected: {}", addr);
+        stream.send(StreamEvent::Close { addr }).await?;
+
+        Ok(())
+    }
+
+    pub fn scanner_route(
+        context: crate::context::ContextWrapped,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("api" / "scanner")
+            .and(warp::ws())
+            .and(remote())
+            .and(warp::query::<ScannerQuery>())
+            .and(Self::with_context(context))
+            .and_then(Self::scanner_handler)
+    }
+
     pub fn websocket_route(
         context: crate::context::ContextWrapped,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
@@ -170,6 +273,13 @@ impl Server {
             .and_then(Self::websocket_handler)
     }
 
+    // Firmware images downloaded by scanners
+    pub fn firmware_route(
+        path: std::path::PathBuf,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("firmware").and(warp::fs::dir(path))
+    }
+
     fn routes(
         &self,
         config: crate::database::config::Server,
@@ -181,6 +291,8 @@ impl Server {
                     number: String::from(env!("CARGO_PKG_VERSION")),
                 })
             })
+            .or(Self::firmware_route(config.base.firmware_dir()))
+            .or(Self::scanner_route(self.context.clone()))
             .or(
                 // WebSocket route
                 Self::websocket_route(self.context.clone())
@@ -195,17 +307,21 @@ impl Server {
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
-        let (mut global_receiver, config) = {
+        let (global_broadcast, config) = {
             let context = self.context.read().await;
             (
-                context.global_broadcast.subscribe(),
+                context.global_broadcast.clone(),
                 context.database.config.clone(),
             )
         };
         let routes = self.routes(config.clone());
-        let (_addr, fut) = warp::serve(routes).bind_with_graceful_shutdown(
-            config.base.port_web.clone(),
-            async move {
+        let only_v6 = config.base.port_web.iter().any(|addr| addr.is_ipv4());
+
+        // Every address gets its own listener, all of them stop together
+        let mut servers = Vec::new();
+        for addr in config.base.port_web.iter() {
+            let mut global_receiver = global_broadcast.subscribe();
+            let shutdown = async move {
                 while let Ok(msg) = global_receiver.recv().await {
                     match msg {
                         shared::messages::global::GlobalMessage::Reload => {
@@ -221,9 +337,197 @@ impl Server {
                         }
                     }
                 }
-            },
-        );
-        fut.await;
+            };
+            match Self::serve(routes.clone(), *addr, only_v6, shutdown) {
+                Ok(server) => {
+                    tracing::info!("WebServer listens on {}", addr);
+                    servers.push(server);
+                }
+                Err(err) => tracing::error!("Unable to bind {} due to: {}", addr, err),
+            }
+        }
+        if servers.is_empty() {
+            anyhow::bail!("Unable to bind any web address");
+        }
+
+        futures::future::join_all(servers).await;
         Ok(())
     }
+
+    // Listener is bound like scanner sockets, IPv6 one is dual-stack only when no IPv4 one is bound
+    fn serve<F>(
+        routes: F,
+        addr: std::net::SocketAddr,
+        only_v6: bool,
+        shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<futures::future::BoxFuture<'static, ()>>
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: warp::Reply,
+    {
+        use warp::hyper::{
+            server::conn::{AddrIncoming, AddrStream},
+            service::{make_service_fn, service_fn, Service},
+        };
+
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        let mut incoming =
+            AddrIncoming::from_listener(tokio::net::TcpListener::from_std(socket.into())?)?;
+        incoming.set_nodelay(true);
+
+        // Peer address is passed to routes in request extensions
+        let service = warp::service(routes);
+        let make = make_service_fn(move |conn: &AddrStream| {
+            let service = service.clone();
+            let remote = Remote(conn.remote_addr());
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |mut request| {
+                    request.extensions_mut().insert(remote);
+                    service.clone().call(request)
+                }))
+            }
+        });
+
+        let server = warp::hyper::Server::builder(incoming)
+            .serve(make)
+            .with_graceful_shutdown(shutdown);
+        Ok(Box::pin(async move {
+            if let Err(err) = server.await {
+                tracing::error!("WebServer failed: {}", err);
+            }
+        }))
+    }
+}
+
+// Address of the connected peer
+#[derive(Debug, Clone, Copy)]
+struct Remote(std::net::SocketAddr);
+
+#[derive(Debug, Default, Deserialize)]
+struct ScannerQuery {
+    token: Option<String>,
+}
+
+impl ScannerQuery {
+    // Connection may send unsigned frames only with the configured token
+    fn trusted(&self, base: &crate::database::config::Base) -> bool {
+        !base.scanner_stream_token.is_empty()
+            && self.token.as_deref() == Some(base.scanner_stream_token.as_str())
+    }
+}
+
+fn remote(
+) -> impl Filter<Extract = (Option<std::net::SocketAddr>,), Error = std::convert::Infallible> + Clone
+{
+    warp::addr::remote()
+        .and(warp::ext::optional::<Remote>())
+        .map(
+            |addr: Option<std::net::SocketAddr>, remote: Option<Remote>| {
+                remote.map(|remote| remote.0).or(addr)
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanner_token() {
+        let mut base = crate::database::config::Base::default();
+        let query = |token: Option<&str>| ScannerQuery {
+            token: token.map(String::from),
+        };
+
+        // No configured token trusts nobody
+        assert!(!query(None).trusted(&base));
+        assert!(!query(Some("")).trusted(&base));
+
+        base.scanner_stream_token = String::from("site-token");
+        assert!(query(Some("site-token")).trusted(&base));
+        assert!(!query(Some("other")).trusted(&base));
+        assert!(!query(None).trusted(&base));
+    }
+
+    #[tokio::test]
+    async fn firmware_download() {
+        let dir = std::env::temp_dir().join(format!("evac-firmware-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.1.0.bin"), b"image").unwrap();
+
+        let route = Server::firmware_route(dir.clone());
+
+        let response = warp::test::request()
+            .path("/firmware/1.1.0.bin")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), b"image");
+
+        let response = warp::test::request()
+            .path("/firmware/2.0.0.bin")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listeners() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let route = warp::path("remote")
+            .and(remote())
+            .map(|addr: Option<std::net::SocketAddr>| {
+                addr.map(|addr| addr.ip().to_string()).unwrap_or_default()
+            });
+
+        // Both wildcards on one port, IPv6 listener leaves IPv4 to the other one
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let stopped = futures::FutureExt::shared(stopped);
+        for addr in [format!("[::]:{}", port), format!("0.0.0.0:{}", port)] {
+            let stopped = stopped.clone();
+            let server = Server::serve(route.clone(), addr.parse().unwrap(), true, async move {
+                let _ = stopped.await;
+            })
+            .unwrap();
+            tokio::spawn(server);
+        }
+        assert!(Server::serve(
+            route.clone(),
+            format!("0.0.0.0:{}", port).parse().unwrap(),
+            true,
+            async {}
+        )
+        .is_err());
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /remote HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("127.0.0.1"));
+        let _ = stop.send(());
+    }
 }
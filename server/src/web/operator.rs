@@ -4,12 +4,14 @@ use crate::{
         entities::{self, Alarm, Device, Role, User},
         LoadSave,
     },
-    message::web::{Auth, Error, UserInfo, Version, WebMessage},
+    message::web::{Auth, Error, FirmwareProgress, ScannerKey, UserInfo, Version, WebMessage},
 };
 use anyhow::Context;
 use mail_send::mail_builder::headers::content_type;
 use rand::distributions::DistString;
-use shared::messages::scanner::{ScannerEvent, ScannerMessage, State};
+use shared::messages::scanner::{
+    capability, ScannerContent, ScannerEvent, ScannerMessage, UpdateState,
+};
 use uuid::timestamp::context;
 pub const ANONYMOUS_USERNAME: &str = "Anonymous";
 pub const ANONYMOUS_UUID: uuid::Uuid = uuid::Uuid::nil();
@@ -69,6 +71,22 @@ impl Operator {
             WebMessage::ScannerSet(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::ScannerRemove(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::ScannerRemoved(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerUnconfirmed(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerOnline(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerOffline(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerRestart(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerIdentify(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerSetKey(..) => has_role(&[Role::Admin]),
+            WebMessage::PendingScannerList(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PendingScannerDetail(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PendingScannerRemoved(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::ScannerAdopt(..) => has_role(&[Role::Admin]),
+            WebMessage::ScannerReject(..) => has_role(&[Role::Admin]),
+            WebMessage::ScannerThrottled(..) => has_role(&[Role::Admin, Role::Service]),
+
+            WebMessage::FirmwareList(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::FirmwareUpdate(..) => has_role(&[Role::Admin]),
+            WebMessage::FirmwareProgress(..) => has_role(&[Role::Admin, Role::Service]),
 
             WebMessage::DeviceDetail(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::DeviceList(..) => has_role(&[Role::Admin, Role::Service]),
@@ -96,6 +114,12 @@ impl Operator {
             WebMessage::AlarmRemove(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::AlarmRemoved(..) => has_role(&[Role::Admin, Role::Service]),
 
+            WebMessage::PatternDetail(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PatternList(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PatternSet(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PatternRemove(..) => has_role(&[Role::Admin, Role::Service]),
+            WebMessage::PatternRemoved(..) => has_role(&[Role::Admin, Role::Service]),
+
             WebMessage::ContactDetail(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::ContactList(..) => has_role(&[Role::Admin, Role::Service]),
             WebMessage::ContactSet(..) => has_role(&[Role::Admin, Role::Service]),
@@ -140,6 +164,22 @@ impl Operator {
             .await?)
     }
     pub async fn login(&self) -> anyhow::Result<()> {
+        // Listing hashes every image, it must not block other clients holding the context
+        let dir = self
+            .context
+            .read()
+            .await
+            .database
+            .config
+            .base
+            .firmware_dir();
+        let firmware = tokio::task::spawn_blocking(move || crate::firmware::list(&dir))
+            .await?
+            .unwrap_or_else(|err| {
+                tracing::error!("Unable to list firmware: {}", err);
+                Vec::new()
+            });
+
         let context = self.context.read().await;
         self.sender
             .send(crate::message::web::WebMessage::Config(
@@ -165,6 +205,22 @@ impl Operator {
             ))
             .await?;
 
+        self.sender
+            .send(crate::message::web::WebMessage::PendingScannerList(
+                context
+                    .database
+                    .data
+                    .pending_scanners
+                    .values()
+                    .cloned()
+                    .collect(),
+            ))
+            .await?;
+
+        self.sender
+            .send(crate::message::web::WebMessage::FirmwareList(firmware))
+            .await?;
+
         self.sender
             .send(crate::message::web::WebMessage::DeviceList(
                 context.database.data.devices.values().cloned().collect(),
@@ -210,6 +266,12 @@ impl Operator {
             ))
             .await?;
 
+        self.sender
+            .send(crate::message::web::WebMessage::PatternList(
+                context.database.data.patterns.values().cloned().collect(),
+            ))
+            .await?;
+
         self.sender
             .send(crate::message::web::WebMessage::NotificationList(
                 context
@@ -272,6 +334,52 @@ impl Operator {
         Ok(())
     }
 
+    // Enabled devices changed, scanners get new allow-list
+    fn filter_events(
+        context: &crate::context::Context,
+        scanner: Option<uuid::Uuid>,
+    ) -> Vec<ScannerEvent> {
+        context
+            .database
+            .scanner_filters()
+            .into_iter()
+            .map(|filter| ScannerEvent {
+                scanner,
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content: ScannerContent::Filter(filter),
+                },
+            })
+            .collect()
+    }
+
+    // Outputs of each scanner, built under the context lock and sent after it
+    fn set_events(
+        states: Vec<(uuid::Uuid, shared::messages::scanner::State)>,
+    ) -> Vec<ScannerEvent> {
+        states
+            .into_iter()
+            .map(|(uuid, state)| ScannerEvent {
+                scanner: Some(uuid),
+                message: ScannerMessage {
+                    uuid: uuid::Uuid::new_v4(),
+                    content: ScannerContent::Set(state),
+                },
+            })
+            .collect()
+    }
+
+    // Called without context lock, Server::run needs it to deliver the queued events
+    async fn send_scanners(
+        sender: &tokio::sync::mpsc::Sender<ScannerEvent>,
+        events: Vec<ScannerEvent>,
+    ) -> anyhow::Result<()> {
+        for event in events {
+            sender.send(event).await?;
+        }
+        Ok(())
+    }
+
     pub async fn process(&mut self, msg: crate::message::web::WebMessage) -> anyhow::Result<()> {
         tracing::debug!("Process message: {:?}", msg);
 
@@ -445,14 +553,20 @@ impl Operator {
                         saved.buzzer = scanner.buzzer;
                         saved.led = scanner.led;
                         saved.scan = scanner.scan;
+                        saved.radio = scanner.radio.clone();
+                        // Pre-shared key is set only by ScannerSetKey
+                        if scanner.public_key.is_some() {
+                            saved.public_key = scanner.public_key.clone();
+                        }
                         saved.clone()
                     } else {
+                        let scanner = scanner.redacted();
                         context
                             .database
                             .data
                             .scanners
                             .insert(scanner.uuid, scanner.clone());
-                        scanner.clone()
+                        scanner
                     };
 
                 // Send new config to scanner
@@ -462,11 +576,7 @@ impl Operator {
                         scanner: Some(scanner.uuid),
                         message: ScannerMessage {
                             uuid: uuid::Uuid::new_v4(),
-                            content: shared::messages::scanner::ScannerContent::Set(State {
-                                scan: Some(scanner.scan),
-                                buzzer: Some(scanner.buzzer),
-                                led: Some(scanner.led),
-                            }),
+                            content: ScannerContent::Set(context.scanner_state(&scanner)),
                         },
                     })
                     .await?;
@@ -480,6 +590,40 @@ impl Operator {
                     .save(&context.database.config.base.data_path)?;
                 Ok(())
             }
+            WebMessage::ScannerSetKey(set) => {
+                let mut context = self.context.write().await;
+                let valid = set.key.as_ref().map_or(true, |key| {
+                    hex::decode(key).is_ok_and(|key| !key.is_empty())
+                });
+                let Some(scanner) = context
+                    .database
+                    .data
+                    .scanners
+                    .get_mut(&set.scanner)
+                    .filter(|_| valid)
+                else {
+                    self.sender
+                        .send(WebMessage::Error(Error::IntegrityError(Box::new(
+                            WebMessage::ScannerSetKey(ScannerKey {
+                                scanner: set.scanner,
+                                key: None,
+                            }),
+                        ))))
+                        .await?;
+                    return Ok(());
+                };
+
+                scanner.key = set.key.clone();
+                let scanner = scanner.clone();
+                context
+                    .web_broadcast
+                    .send(WebMessage::ScannerDetail(scanner))?;
+                context
+                    .database
+                    .data
+                    .save(&context.database.config.base.data_path)?;
+                Ok(())
+            }
             WebMessage::ScannerRemove(uuid) => {
                 let mut context = self.context.write().await;
                 context.database.data.scanners.remove(&uuid);
@@ -495,25 +639,193 @@ impl Operator {
 
                 Ok(())
             }
+            WebMessage::ScannerAdopt(adopt) => {
+                let mut context = self.context.write().await;
+                let Some(pending) = context.database.data.pending_scanners.remove(&adopt.uuid)
+                else {
+                    return Ok(());
+                };
+
+                let scanner = entities::Scanner {
+                    uuid: pending.uuid,
+                    name: adopt.name.clone(),
+                    room: adopt.room,
+                    ip: pending.ip,
+                    port: pending.port,
+                    mac: pending.mac,
+                    last_activity: pending.last_seen,
+                    protocol: pending.protocol,
+                    firmware: pending.firmware,
+                    capabilities: pending.capabilities,
+                    ..Default::default()
+                };
+                context
+                    .database
+                    .data
+                    .scanners
+                    .insert(scanner.uuid, scanner.clone());
+
+                // Initial config and allow-list for scanner
+                let mut events = vec![ScannerEvent {
+                    scanner: Some(scanner.uuid),
+                    message: ScannerMessage {
+                        uuid: uuid::Uuid::new_v4(),
+                        content: ScannerContent::Set(context.scanner_state(&scanner)),
+                    },
+                }];
+                events.extend(Self::filter_events(&context, Some(scanner.uuid)));
+
+                context
+                    .web_broadcast
+                    .send(WebMessage::PendingScannerRemoved(scanner.uuid))?;
+                context
+                    .web_broadcast
+                    .send(WebMessage::ScannerDetail(scanner))?;
+                context
+                    .database
+                    .data
+                    .save(&context.database.config.base.data_path)?;
+
+                let scanner_sender = context.scanner_sender.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await
+            }
+            WebMessage::ScannerReject(uuid) => {
+                let mut context = self.context.write().await;
+                let web_broadcast = context.web_broadcast.clone();
+                if let Some(pending) = context.database.data.pending_scanners.get_mut(uuid) {
+                    pending.rejected = true;
+
+                    web_broadcast.send(WebMessage::PendingScannerDetail(pending.clone()))?;
+                    context
+                        .database
+                        .data
+                        .save(&context.database.config.base.data_path)?;
+                }
+
+                Ok(())
+            }
+            WebMessage::FirmwareUpdate(update) => {
+                let (dir, url) = {
+                    let base = &self.context.read().await.database.config.base;
+                    (base.firmware_dir(), base.firmware_url())
+                };
+                let version = update.version.clone();
+                let firmware =
+                    tokio::task::spawn_blocking(move || crate::firmware::find(&dir, &version))
+                        .await?;
+
+                let context = self.context.read().await;
+                let (firmware, url) = match (firmware, url) {
+                    (Ok(firmware), Some(url)) => (firmware, url),
+                    (firmware, url) => {
+                        tracing::error!(
+                            "Unable to start firmware update: {:?} {:?}",
+                            firmware.err(),
+                            url
+                        );
+                        self.sender
+                            .send(WebMessage::Error(Error::IntegrityError(Box::new(
+                                msg.clone(),
+                            ))))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                let sha256 = hex::decode(&firmware.sha256)?;
+
+                for scanner in context.database.data.scanners.values().filter(|s| {
+                    update.scanners.contains(&s.uuid)
+                        || s.room.is_some_and(|room| update.rooms.contains(&room))
+                }) {
+                    if !scanner.has_capability(capability::UPDATE) {
+                        context.web_broadcast.send(WebMessage::FirmwareProgress(
+                            FirmwareProgress {
+                                scanner: scanner.uuid,
+                                version: firmware.version.clone(),
+                                state: UpdateState::Failed(String::from("Update is not supported")),
+                                progress: 0,
+                            },
+                        ))?;
+                        continue;
+                    }
+
+                    context
+                        .scanner_sender
+                        .send(ScannerEvent {
+                            scanner: Some(scanner.uuid),
+                            message: ScannerMessage {
+                                uuid: uuid::Uuid::new_v4(),
+                                content: ScannerContent::Update {
+                                    url: format!("{}/{}", url, firmware.file),
+                                    sha256: sha256.clone(),
+                                    version: firmware.version.clone(),
+                                },
+                            },
+                        })
+                        .await?;
+                }
+
+                Ok(())
+            }
+            WebMessage::ScannerRestart(uuid) | WebMessage::ScannerIdentify(uuid) => {
+                let context = self.context.read().await;
+                if !context.database.data.scanners.contains_key(uuid) {
+                    return Ok(());
+                }
+
+                let content = if let WebMessage::ScannerIdentify(..) = msg {
+                    ScannerContent::Identify(context.database.config.base.scanner_identify)
+                } else {
+                    ScannerContent::Restart
+                };
+
+                context
+                    .scanner_sender
+                    .send(ScannerEvent {
+                        scanner: Some(*uuid),
+                        message: ScannerMessage {
+                            uuid: uuid::Uuid::new_v4(),
+                            content,
+                        },
+                    })
+                    .await?;
+
+                Ok(())
+            }
             WebMessage::DeviceSet(device) => {
                 let mut context = self.context.write().await;
                 let web_broadcast = context.web_broadcast.clone();
+                let mut events = Vec::new();
                 if let Some(saved) = context.database.data.devices.get_mut(&device.uuid) {
+                    let changed = saved.enabled != device.enabled;
                     saved.name = device.name.clone();
                     saved.enabled = device.enabled;
+                    saved.device_type = device.device_type.clone();
 
                     web_broadcast.send(WebMessage::DeviceDetail(saved.clone()))?;
                     context
                         .database
                         .data
                         .save(&context.database.config.base.data_path)?;
+
+                    if changed {
+                        events = Self::filter_events(&context, None);
+                    }
                 }
 
-                Ok(())
+                let scanner_sender = context.scanner_sender.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await
             }
             WebMessage::DeviceRemove(uuid) => {
                 let mut context = self.context.write().await;
-                context.database.data.devices.remove(&uuid);
+                let mut events = Vec::new();
+                if let Some(device) = context.database.data.devices.remove(&uuid) {
+                    if device.enabled {
+                        events = Self::filter_events(&context, None);
+                    }
+                }
                 context
                     .web_broadcast
                     .send(crate::message::web::WebMessage::DeviceRemoved(uuid.clone()))?;
@@ -521,7 +833,10 @@ impl Operator {
                     .database
                     .data
                     .save(&context.database.config.base.data_path)?;
-                Ok(())
+
+                let scanner_sender = context.scanner_sender.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await
             }
 
             WebMessage::EventRemove(uuid) => {
@@ -537,13 +852,18 @@ impl Operator {
             WebMessage::AlarmSet(alarm) => {
                 let mut context = self.context.write().await;
                 let web_broadcast = context.web_broadcast.clone();
+                let previous = context.database.data.alarms.get(&alarm.uuid).cloned();
 
                 if let Some(saved) = context.database.data.alarms.get_mut(&alarm.uuid) {
                     saved.name = alarm.name.clone();
                     saved.notification = alarm.notification;
                     saved.led = alarm.led;
                     saved.buzzer = alarm.buzzer;
+                    saved.pattern = alarm.pattern;
                     saved.group = alarm.group;
+                    saved.locations = alarm.locations.clone();
+                    saved.rooms = alarm.rooms.clone();
+                    saved.priority = alarm.priority;
 
                     web_broadcast.send(WebMessage::AlarmDetail(saved.clone()))?;
                 } else {
@@ -555,12 +875,24 @@ impl Operator {
                     web_broadcast.send(WebMessage::AlarmDetail(alarm.clone()))?;
                 }
 
+                // Sounding alarm takes the new priority, pattern and scope right away
+                let events = match previous {
+                    Some(previous) => Self::set_events(crate::alarm::changed(
+                        &context.database.data,
+                        &context.alarms,
+                        &previous,
+                    )),
+                    None => Vec::new(),
+                };
+
                 context
                     .database
                     .data
                     .save(&context.database.config.base.data_path)?;
 
-                Ok(())
+                let scanner_sender = context.scanner_sender.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await
             }
             WebMessage::AlarmRemove(uuid) => {
                 let mut context = self.context.write().await;
@@ -575,6 +907,55 @@ impl Operator {
                 Ok(())
             }
 
+            WebMessage::PatternSet(pattern) => {
+                let mut context = self.context.write().await;
+                let pattern =
+                    if let Some(saved) = context.database.data.patterns.get_mut(&pattern.uuid) {
+                        saved.name = pattern.name.clone();
+                        saved.buzzer = pattern.buzzer.clone();
+                        saved.led = pattern.led.clone();
+                        saved.clone()
+                    } else {
+                        context
+                            .database
+                            .data
+                            .patterns
+                            .insert(pattern.uuid, pattern.clone());
+                        pattern.clone()
+                    };
+
+                context
+                    .web_broadcast
+                    .send(WebMessage::PatternDetail(pattern))?;
+                context
+                    .database
+                    .data
+                    .save(&context.database.config.base.data_path)?;
+
+                Ok(())
+            }
+            WebMessage::PatternRemove(uuid) => {
+                let mut context = self.context.write().await;
+                if !context.database.data.is_pattern_used(uuid) {
+                    context.database.data.patterns.remove(&uuid);
+                    context
+                        .web_broadcast
+                        .send(WebMessage::PatternRemoved(uuid.clone()))?;
+                    context
+                        .database
+                        .data
+                        .save(&context.database.config.base.data_path)?;
+                } else {
+                    self.sender
+                        .send(WebMessage::Error(Error::IntegrityError(Box::new(
+                            msg.clone(),
+                        ))))
+                        .await?;
+                }
+
+                Ok(())
+            }
+
             WebMessage::Alarm(info) => {
                 // Set the alarm
                 let mut context = self.context.write().await;
@@ -592,38 +973,27 @@ impl Operator {
                     .get(&alarm.notification)
                     .cloned()
                     .context("Email does not exist")?;
-
                 context.alarms.insert(info.uuid, info.clone());
 
                 let contacts = context.database.data.get_contacts_by_group(alarm.group);
 
-                context.database.data.scanners.values_mut().for_each(|s| {
-                    s.buzzer = alarm.buzzer;
-                    s.led = alarm.led
-                });
-                context
-                    .scanner_sender
-                    .send(ScannerEvent {
-                        scanner: None,
-                        message: ScannerMessage {
-                            uuid: uuid::Uuid::new_v4(),
-                            content: shared::messages::scanner::ScannerContent::Set(State {
-                                scan: None,
-                                buzzer: Some(alarm.buzzer),
-                                led: Some(alarm.led),
-                            }),
-                        },
-                    })
-                    .await?;
+                // Only scanners in the alarm scope change, other alarms may outrank this one
+                let events = Self::set_events(crate::alarm::outputs(
+                    &context.database.data,
+                    &context.alarms,
+                    context.database.data.alarm_scanners(&alarm),
+                ));
                 context
                     .web_broadcast
                     .send(WebMessage::Alarm(info.clone()))?;
 
+                let scanner_sender = context.scanner_sender.clone();
+                let config = context.database.config.notification.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await?;
+
                 for contact in contacts {
-                    context
-                        .database
-                        .config
-                        .notification
+                    config
                         .send_alarm(contact, notification.clone(), info.clone())
                         .await?;
                 }
@@ -634,34 +1004,30 @@ impl Operator {
             WebMessage::AlarmStop(alarm) => {
                 let mut context = self.context.write().await;
                 // Mark alarm as done
-                context.alarms.remove(&alarm);
-                // Set scanners to silent mode
-                context.database.data.scanners.values_mut().for_each(|s| {
-                    s.buzzer = false;
-                    s.led = false;
-                });
-                // Send message to scanners
-                context
-                    .scanner_sender
-                    .send(ScannerEvent {
-                        scanner: None,
-                        message: ScannerMessage {
-                            uuid: uuid::Uuid::new_v4(),
-                            content: shared::messages::scanner::ScannerContent::Set(State {
-                                scan: None,
-                                buzzer: Some(false),
-                                led: Some(false),
-                            }),
-                        },
-                    })
-                    .await?;
+                let scope = context
+                    .alarms
+                    .remove(&alarm)
+                    .and_then(|info| context.database.data.alarms.get(&info.alarm).cloned());
+                // Restore scanners in the alarm scope, all of them when the alarm is unknown
+                let scanners = match scope {
+                    Some(scope) => context.database.data.alarm_scanners(&scope),
+                    None => context.database.data.scanners.keys().cloned().collect(),
+                };
+                // Remaining alarms keep sounding
+                let events = Self::set_events(crate::alarm::outputs(
+                    &context.database.data,
+                    &context.alarms,
+                    scanners,
+                ));
 
                 // Send message to web clients
                 context
                     .web_broadcast
                     .send(crate::message::web::WebMessage::AlarmStop(alarm.clone()))?;
 
-                Ok(())
+                let scanner_sender = context.scanner_sender.clone();
+                drop(context);
+                Self::send_scanners(&scanner_sender, events).await
             }
 
             WebMessage::Notify { uuid, group } => {
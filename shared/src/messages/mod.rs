@@ -1,2 +1,4 @@
 pub mod global;
+pub mod pattern;
 pub mod scanner;
+pub mod wrapped;
\ No newline at end of file
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+// Morse SOS in ms, three dots, three dashes, three dots and a pause before repeating
+const SOS: [u32; 18] = [
+    200, 200, 200, 200, 200, 600, 600, 200, 600, 200, 600, 600, 200, 200, 200, 200, 200, 1400,
+];
+
+// Shape of buzzer or LED output
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Signal {
+    #[default]
+    Continuous,
+    // On and off time in ms
+    Pulsed {
+        on: u32,
+        off: u32,
+    },
+    Sos,
+    // On and off times in ms alternating from on, repeated
+    Sequence(Vec<u32>),
+}
+
+impl Signal {
+    // Output level at ms since the signal started
+    pub fn level(&self, elapsed: u64) -> bool {
+        match self {
+            Signal::Continuous => true,
+            Signal::Pulsed { on, off } => Self::step(&[*on, *off], elapsed),
+            Signal::Sos => Self::step(&SOS, elapsed),
+            Signal::Sequence(steps) => Self::step(steps, elapsed),
+        }
+    }
+
+    fn step(steps: &[u32], elapsed: u64) -> bool {
+        let period: u64 = steps.iter().map(|step| *step as u64).sum();
+        if period == 0 {
+            return false;
+        }
+
+        let mut position = elapsed % period;
+        for (index, step) in steps.iter().enumerate() {
+            if position < *step as u64 {
+                return index % 2 == 0;
+            }
+            position -= *step as u64;
+        }
+        false
+    }
+}
+
+// Signal played for duration in seconds, 0 plays until the output is changed
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+#[serde(default)]
+pub struct Pattern {
+    pub signal: Signal,
+    pub duration: u32,
+}
+
+impl Pattern {
+    // Output level at ms since the pattern started, None once it is over
+    pub fn level(&self, elapsed: u64) -> Option<bool> {
+        if self.duration > 0 && elapsed >= self.duration as u64 * 1000 {
+            None
+        } else {
+            Some(self.signal.level(elapsed))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(signal: &Signal, step: u64, count: u64) -> Vec<bool> {
+        (0..count).map(|i| signal.level(i * step)).collect()
+    }
+
+    #[test]
+    fn continuous_and_pulsed() {
+        assert_eq!(levels(&Signal::Continuous, 1000, 3), vec![true; 3]);
+
+        let pulsed = Signal::Pulsed { on: 300, off: 100 };
+        assert_eq!(
+            levels(&pulsed, 100, 8),
+            vec![true, true, true, false, true, true, true, false]
+        );
+        assert!(!Signal::Pulsed { on: 0, off: 0 }.level(0));
+    }
+
+    #[test]
+    fn sos() {
+        // Dots, dashes, dots and the pause in 200 ms slots
+        let expected = "10101000111011101110001010100000001010";
+        let actual: String = levels(&Signal::Sos, 200, expected.len() as u64)
+            .into_iter()
+            .map(|on| if on { '1' } else { '0' })
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn sequence_with_duration() {
+        let pattern = Pattern {
+            signal: Signal::Sequence(vec![100, 200, 300]),
+            duration: 2,
+        };
+
+        assert_eq!(pattern.level(0), Some(true));
+        assert_eq!(pattern.level(150), Some(false));
+        assert_eq!(pattern.level(450), Some(true));
+        // Next period starts right after the last step
+        assert_eq!(pattern.level(650), Some(true));
+        assert_eq!(pattern.level(1999), Some(false));
+        assert_eq!(pattern.level(2000), None);
+        assert!(!Signal::Sequence(Vec::new()).level(10));
+    }
+}
@@ -1,15 +1,285 @@
-use serde::{de, Deserialize, Serialize};
+use serde::{de, ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use std::{
     default,
     net::{Ipv4Addr, SocketAddrV4},
 };
 use uuid::Uuid;
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+use super::pattern::Pattern;
+
+// Version of the scanner protocol, scanners without version are 0
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Capability bits announced by scanner in Register
+pub mod capability {
+    pub const BUZZER: u32 = 1 << 0;
+    pub const LED: u32 = 1 << 1;
+    pub const SCAN: u32 = 1 << 2;
+    pub const BATCHING: u32 = 1 << 3;
+    pub const SIGNING: u32 = 1 << 4;
+    pub const ENCRYPTION: u32 = 1 << 5;
+    // Scanner answers Set with Ok
+    pub const ACK: u32 = 1 << 6;
+    // Scanner answers Ping with Pong
+    pub const PING: u32 = 1 << 7;
+    // Scanner handles Restart and Identify
+    pub const RESTART: u32 = 1 << 8;
+    pub const IDENTIFY: u32 = 1 << 9;
+    // Scanner downloads and installs firmware on Update
+    pub const UPDATE: u32 = 1 << 10;
+    // Signed messages carry a sequence number
+    pub const SEQUENCE: u32 = 1 << 11;
+    // Scanner forwards only advertisements passing Filter
+    pub const FILTER: u32 = 1 << 12;
+    // Scanner applies Radio parameters from Set
+    pub const RADIO: u32 = 1 << 13;
+    // Scanner plays buzzer and LED patterns from Set
+    pub const PATTERN: u32 = 1 << 14;
+    // Scanner answers TimedPing with TimedPong and stamps scan results with its clock
+    pub const CLOCK: u32 = 1 << 15;
+
+    // Firmware before protocol versioning
+    pub const LEGACY: u32 = BUZZER | LED | SCAN;
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
 pub struct State {
     pub scan: Option<bool>,
     pub led: Option<bool>,
     pub buzzer: Option<bool>,
+    #[serde(default)]
+    pub radio: Option<Radio>,
+    // Shape of the output when it is turned on, continuous without pattern
+    #[serde(default)]
+    pub buzzer_pattern: Option<Pattern>,
+    #[serde(default)]
+    pub led_pattern: Option<Pattern>,
+}
+
+// Optional fields are sent only up to the last one set, older firmware expects three fields
+impl Serialize for State {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let optional = if self.led_pattern.is_some() {
+            3
+        } else if self.buzzer_pattern.is_some() {
+            2
+        } else if self.radio.is_some() {
+            1
+        } else {
+            0
+        };
+
+        let mut state = serializer.serialize_struct("State", 3 + optional)?;
+        state.serialize_field("scan", &self.scan)?;
+        state.serialize_field("led", &self.led)?;
+        state.serialize_field("buzzer", &self.buzzer)?;
+        if optional >= 1 {
+            state.serialize_field("radio", &self.radio)?;
+        } else {
+            state.skip_field("radio")?;
+        }
+        if optional >= 2 {
+            state.serialize_field("buzzer_pattern", &self.buzzer_pattern)?;
+        } else {
+            state.skip_field("buzzer_pattern")?;
+        }
+        if optional >= 3 {
+            state.serialize_field("led_pattern", &self.led_pattern)?;
+        } else {
+            state.skip_field("led_pattern")?;
+        }
+        state.end()
+    }
+}
+
+// BLE scan parameters, unset ones keep their current value
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Radio {
+    // Scan interval and window in units of 0.625 ms, window is at most interval
+    pub interval: Option<u16>,
+    pub window: Option<u16>,
+    // Active scanning requests scan responses with names
+    pub active: Option<bool>,
+    pub filter_duplicates: Option<bool>,
+    // Weaker advertisements are not forwarded
+    pub min_rssi: Option<i32>,
+}
+
+impl Radio {
+    // Values of self override the other ones
+    pub fn merge(&self, other: &Radio) -> Radio {
+        Radio {
+            interval: self.interval.or(other.interval),
+            window: self.window.or(other.window),
+            active: self.active.or(other.active),
+            filter_duplicates: self.filter_duplicates.or(other.filter_duplicates),
+            min_rssi: self.min_rssi.or(other.min_rssi),
+        }
+    }
+}
+
+// Advertisements scanner forwards, the others only as a sample for discovery
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Filter {
+    pub macs: Vec<Vec<u8>>,
+    // Leading bytes of mac, like vendor OUI
+    pub prefixes: Vec<Vec<u8>>,
+    // 16-bit UUIDs of advertised services or service data
+    pub services: Vec<u16>,
+    // Forward every n-th advertisement which does not match, 0 drops them
+    pub sample: u32,
+    // Set when the allow-list does not fit into one message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<FilterPart>,
+}
+
+// Scanner applies split allow-list once all parts of the revision arrived
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub struct FilterPart {
+    pub revision: u32,
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Filter {
+    // Parts which fit into one message, prefixes and services go with the first one
+    pub fn split(self, revision: u32, fits: impl Fn(&Filter) -> bool) -> Vec<Filter> {
+        if fits(&self) {
+            return vec![self];
+        }
+
+        // Largest values are measured, final ones cannot make a part longer
+        let part = Some(FilterPart {
+            revision,
+            index: u32::MAX,
+            count: u32::MAX,
+        });
+        let mut parts = Vec::new();
+        let mut current = Filter {
+            macs: Vec::new(),
+            prefixes: self.prefixes,
+            services: self.services,
+            sample: self.sample,
+            part: part.clone(),
+        };
+        for mac in self.macs {
+            current.macs.push(mac);
+            if !fits(&current) && current.macs.len() > 1 {
+                let mac = current.macs.pop().unwrap();
+                let next = Filter {
+                    macs: vec![mac],
+                    prefixes: Vec::new(),
+                    services: Vec::new(),
+                    sample: self.sample,
+                    part: part.clone(),
+                };
+                parts.push(std::mem::replace(&mut current, next));
+            }
+        }
+        parts.push(current);
+
+        let count = parts.len() as u32;
+        for (index, filter) in parts.iter_mut().enumerate() {
+            filter.part = Some(FilterPart {
+                revision,
+                index: index as u32,
+                count,
+            });
+        }
+        parts
+    }
+
+    // Whole allow-list from parts of one revision, none while some are missing
+    pub fn assemble(parts: &[Filter]) -> Option<Filter> {
+        let revision = parts.first()?.part.as_ref()?.revision;
+        let mut ordered = vec![None; parts.first()?.part.as_ref()?.count as usize];
+        for filter in parts {
+            let part = filter.part.as_ref()?;
+            if part.revision != revision || part.count as usize != ordered.len() {
+                return None;
+            }
+            *ordered.get_mut(part.index as usize)? = Some(filter);
+        }
+
+        let ordered: Vec<&Filter> = ordered.into_iter().collect::<Option<_>>()?;
+        let first = ordered.first()?;
+        Some(Filter {
+            macs: ordered
+                .iter()
+                .flat_map(|filter| filter.macs.iter().cloned())
+                .collect(),
+            prefixes: first.prefixes.clone(),
+            services: first.services.clone(),
+            sample: first.sample,
+            part: None,
+        })
+    }
+
+    pub fn matches(&self, mac: &[u8], data: &[u8]) -> bool {
+        self.macs.iter().any(|m| m == mac)
+            || self.prefixes.iter().any(|p| mac.starts_with(p))
+            || (!self.services.is_empty()
+                && Self::services(data).any(|uuid| self.services.contains(&uuid)))
+    }
+
+    // Walk length-tag-value structures of advertisement payload
+    fn services(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+        let mut position = 0;
+        std::iter::from_fn(move || {
+            let length = *data.get(position)? as usize;
+            let chunk = data.get(position + 1..position + 1 + length)?;
+            position += length + 1;
+            Some(chunk)
+        })
+        .flat_map(|chunk| {
+            let (tag, value) = chunk.split_first().unwrap_or((&0, &[]));
+            let value = match tag {
+                // Incomplete and complete list of 16-bit service UUIDs
+                0x02 | 0x03 => value,
+                // Service data, UUID first
+                0x16 => &value[..value.len().min(2)],
+                _ => &[],
+            };
+            value
+                .chunks_exact(2)
+                .map(|uuid| u16::from_le_bytes([uuid[0], uuid[1]]))
+        })
+    }
+}
+
+// Periodic scanner health report
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Status {
+    // Seconds since boot
+    pub uptime: u64,
+    pub firmware: String,
+    pub free_heap: u32,
+    // Advertisements seen and results which could not be sent since boot
+    pub scans: u64,
+    pub dropped: u64,
+    pub link: bool,
+    pub state: State,
+}
+
+// Firmware update progress, Pending and Done are decided by server
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub enum UpdateState {
+    #[default]
+    Pending,
+    Downloading,
+    Verifying,
+    Installing,
+    Done,
+    Failed(String),
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
+pub struct UpdateStatus {
+    pub version: String,
+    pub state: UpdateState,
+    // Percent of downloaded image
+    pub progress: u8,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
@@ -19,11 +289,24 @@ pub enum Value {
     U64(u64),
 }
 
+// Maximal size of encoded scan results in one datagram, leaves room for envelope under MTU
+pub const SCAN_BATCH_SIZE: usize = 1200;
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
 pub struct ScanDevice {
     pub mac: Vec<u8>,
     pub rssi: i32,
     pub data: Vec<u8>,
+    // Milliseconds between the capture and sending of the result
+    #[serde(default)]
+    pub age: u32,
+    // Scanner clock in ms at the capture, server corrects it by the offset from Ping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
@@ -33,6 +316,9 @@ pub enum ScannerWrapped {
         nonce: Vec<u8>,
         message: Vec<u8>,
         hash: Vec<u8>,
+        // Monotonic per sender, zero is not sent and means no replay protection
+        #[serde(default, skip_serializing_if = "is_zero")]
+        sequence: u64,
     },
     Encrypted {
         encrypted: Vec<u8>,
@@ -50,6 +336,12 @@ pub enum ScannerContent {
     Hello,
     Register {
         mac: Vec<u8>,
+        #[serde(default)]
+        protocol: u32,
+        #[serde(default)]
+        firmware: String,
+        #[serde(default)]
+        capabilities: u32,
     },
     Ping(String),
     Pong(String),
@@ -57,6 +349,24 @@ pub enum ScannerContent {
 
     Set(State),
     ScanResult(ScanDevice),
+    ScanResultBatch(Vec<ScanDevice>),
+    // Blink LED for given number of seconds
+    Identify(u32),
+    Status(Status),
+    Update {
+        url: String,
+        sha256: Vec<u8>,
+        version: String,
+    },
+    UpdateStatus(UpdateStatus),
+    Filter(Filter),
+    // Pong with scanner clock in ms when Ping was answered
+    TimedPong {
+        payload: String,
+        time: u64,
+    },
+    // Ping of server that understands TimedPong, sent only to scanners with CLOCK
+    TimedPing(String),
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord)]
@@ -70,3 +380,162 @@ pub struct ScannerEvent {
     pub scanner: Option<uuid::Uuid>,
     pub message: ScannerMessage,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::pattern::Signal;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct LegacyState {
+        scan: Option<bool>,
+        led: Option<bool>,
+        buzzer: Option<bool>,
+    }
+
+    #[test]
+    fn state_compatibility() {
+        let legacy = LegacyState {
+            scan: Some(true),
+            led: None,
+            buzzer: Some(false),
+        };
+        let state = State {
+            scan: Some(true),
+            led: None,
+            buzzer: Some(false),
+            ..Default::default()
+        };
+
+        let data = rmp_serde::to_vec(&state).unwrap();
+        assert_eq!(data, rmp_serde::to_vec(&legacy).unwrap());
+        assert_eq!(rmp_serde::from_slice::<LegacyState>(&data).unwrap(), legacy);
+        assert_eq!(rmp_serde::from_slice::<State>(&data).unwrap(), state);
+
+        let state = State {
+            radio: Some(Radio {
+                interval: Some(160),
+                min_rssi: Some(-80),
+                ..Default::default()
+            }),
+            ..state
+        };
+        let data = rmp_serde::to_vec(&state).unwrap();
+        assert_eq!(rmp_serde::from_slice::<State>(&data).unwrap(), state);
+
+        // Unset radio in front of pattern keeps its place
+        let state = State {
+            radio: None,
+            led_pattern: Some(Pattern {
+                signal: Signal::Sos,
+                duration: 30,
+            }),
+            ..state
+        };
+        let data = rmp_serde::to_vec(&state).unwrap();
+        assert_eq!(rmp_serde::from_slice::<State>(&data).unwrap(), state);
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<State>(&json).unwrap(), state);
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct LegacyScanDevice {
+        mac: Vec<u8>,
+        rssi: i32,
+        data: Vec<u8>,
+        age: u32,
+    }
+
+    #[test]
+    fn scan_device_compatibility() {
+        let legacy = LegacyScanDevice {
+            mac: vec![1, 2, 3, 4, 5, 6],
+            rssi: -60,
+            data: vec![2, 1, 6],
+            age: 20,
+        };
+        let device = ScanDevice {
+            mac: legacy.mac.clone(),
+            rssi: legacy.rssi,
+            data: legacy.data.clone(),
+            age: legacy.age,
+            timestamp: None,
+        };
+
+        // Results without scanner clock look like before
+        let data = rmp_serde::to_vec(&device).unwrap();
+        assert_eq!(data, rmp_serde::to_vec(&legacy).unwrap());
+        assert_eq!(rmp_serde::from_slice::<ScanDevice>(&data).unwrap(), device);
+
+        let device = ScanDevice {
+            timestamp: Some(5_000),
+            ..device
+        };
+        let data = rmp_serde::to_vec(&device).unwrap();
+        assert_eq!(rmp_serde::from_slice::<ScanDevice>(&data).unwrap(), device);
+    }
+
+    #[test]
+    fn filter_matches() {
+        let filter = Filter {
+            macs: vec![vec![1, 2, 3, 4, 5, 6]],
+            prefixes: vec![vec![0x3c, 0xe9]],
+            services: vec![0xfcd2],
+            sample: 0,
+            part: None,
+        };
+
+        assert!(filter.matches(&[1, 2, 3, 4, 5, 6], &[]));
+        assert!(filter.matches(&[0x3c, 0xe9, 0, 0, 0, 1], &[]));
+        assert!(!filter.matches(&[1, 2, 3, 4, 5, 7], &[]));
+
+        // Flags, name and Shelly service data
+        let data = [
+            2, 0x01, 0x06, 3, 0x09, b'B', b'T', 5, 0x16, 0xd2, 0xfc, 0x40, 0x00,
+        ];
+        assert!(filter.matches(&[9; 6], &data));
+        // Service list and truncated payload
+        assert!(filter.matches(&[9; 6], &[5, 0x03, 0x0f, 0x18, 0xd2, 0xfc]));
+        assert!(!filter.matches(&[9; 6], &[5, 0x03, 0x0f, 0x18, 0xd2]));
+        assert!(!filter.matches(&[9; 6], &data[..7]));
+    }
+
+    #[test]
+    fn filter_parts() {
+        let filter = Filter {
+            macs: (0..200u8)
+                .map(|i| vec![0xc0, 0xff, 0xee, 0, 1, i])
+                .collect(),
+            prefixes: vec![vec![0x3c, 0xe9]],
+            services: vec![0xfcd2],
+            sample: 10,
+            part: None,
+        };
+
+        // Short allow-list stays in one message, encoded as before the parts
+        let short = Filter {
+            macs: filter.macs[..2].to_vec(),
+            ..filter.clone()
+        };
+        let fits = |filter: &Filter| rmp_serde::to_vec(filter).unwrap().len() <= 512;
+        assert_eq!(short.clone().split(1, fits), vec![short.clone()]);
+        assert_eq!(
+            rmp_serde::to_vec(&short).unwrap().len(),
+            rmp_serde::to_vec(&(&short.macs, &short.prefixes, &short.services, 10))
+                .unwrap()
+                .len()
+        );
+
+        let mut parts = filter.clone().split(7, fits);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(fits));
+
+        // Parts may arrive in any order, missing or foreign part keeps the allow-list incomplete
+        parts.reverse();
+        assert_eq!(Filter::assemble(&parts), Some(filter.clone()));
+        assert_eq!(Filter::assemble(&parts[1..]), None);
+        let mut foreign = parts.clone();
+        foreign[0].part.as_mut().unwrap().revision = 8;
+        assert_eq!(Filter::assemble(&foreign), None);
+    }
+}
@@ -0,0 +1,369 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use super::scanner::{ScannerMessage, ScannerWrapped};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WrapError {
+    Encode,
+    Decode,
+    MissingKey,
+    Signature,
+    Key,
+    Crypto,
+    Unsupported,
+}
+
+impl std::fmt::Display for WrapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WrapError::Encode => write!(f, "Unable to encode scanner message"),
+            WrapError::Decode => write!(f, "Unable to decode scanner message"),
+            WrapError::MissingKey => write!(f, "Scanner key is missing"),
+            WrapError::Signature => write!(f, "Invalid scanner message signature"),
+            WrapError::Key => write!(f, "Invalid scanner encryption key"),
+            WrapError::Crypto => write!(f, "Unable to decrypt scanner message"),
+            WrapError::Unsupported => write!(f, "Unsupported scanner envelope"),
+        }
+    }
+}
+
+impl std::error::Error for WrapError {}
+
+// Nonce of unsequenced messages, it is 16 bytes of uuid since the first signing firmware
+const NONCE_SIZE: usize = 16;
+// Domain of sequenced signatures, byte 0xc1 is never valid MessagePack, so no sequenced
+// signature is also an unsequenced one over a 16 byte nonce and a decodable message
+const SEQUENCED: &[u8] = b"evac scanner seq\xc1";
+
+fn sign(key: &[u8], nonce: &[u8], message: &[u8], sequence: u64) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    // Unsequenced signature stays the same as before sequences
+    if sequence == 0 {
+        mac.update(nonce);
+        mac.update(message);
+        return mac;
+    }
+
+    mac.update(SEQUENCED);
+    for field in [nonce, message] {
+        mac.update(&(field.len() as u32).to_be_bytes());
+        mac.update(field);
+    }
+    mac.update(&sequence.to_be_bytes());
+    mac
+}
+
+fn key32(key: &[u8]) -> Result<[u8; 32], WrapError> {
+    key.try_into().map_err(|_| WrapError::Key)
+}
+
+// Every message has its own ephemeral key, so the nonce can stay constant
+fn cipher(shared: &[u8], ephemeral: &[u8], recipient: &[u8]) -> ChaCha20Poly1305 {
+    let mut salt = ephemeral.to_vec();
+    salt.extend_from_slice(recipient);
+
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"evac scanner", &mut key)
+        .expect("32 bytes is a valid HKDF output");
+    ChaCha20Poly1305::new(&key.into())
+}
+
+// Generate a new X25519 secret key
+pub fn secret_key() -> Vec<u8> {
+    StaticSecret::random_from_rng(rand_core::OsRng)
+        .to_bytes()
+        .to_vec()
+}
+
+// Derive X25519 public key from the secret key
+pub fn public_key(secret: &[u8]) -> Result<Vec<u8>, WrapError> {
+    let secret = StaticSecret::from(key32(secret)?);
+    Ok(PublicKey::from(&secret).as_bytes().to_vec())
+}
+
+// Largest datagram carrying message of this many encoded bytes, signed with sequence and encrypted
+pub fn max_size(message: usize) -> usize {
+    // Bytes are encoded one by one, the ones above 127 take two
+    let encoded =
+        |wrapped: &ScannerWrapped| rmp_serde::to_vec(wrapped).map_or(usize::MAX, |d| d.len());
+    let hashed = encoded(&ScannerWrapped::Hashed {
+        nonce: vec![0xff; 16],
+        message: vec![0xff; message],
+        hash: vec![0xff; 32],
+        sequence: u64::MAX,
+    });
+    // Authentication tag is appended to the encrypted envelope
+    encoded(&ScannerWrapped::Encrypted {
+        encrypted: vec![0xff; hashed.saturating_add(16)],
+        pub_key: vec![0xff; 32],
+    })
+}
+
+impl ScannerWrapped {
+    pub fn plain(message: &ScannerMessage) -> Result<Self, WrapError> {
+        Ok(ScannerWrapped::Plain(
+            rmp_serde::to_vec(message).map_err(|_| WrapError::Encode)?,
+        ))
+    }
+
+    // HMAC-SHA256 over nonce + message, nonce is random per datagram
+    pub fn hashed(message: &ScannerMessage, key: &[u8]) -> Result<Self, WrapError> {
+        Self::sequenced(message, key, 0)
+    }
+
+    // Signed message with sequence number for replay protection
+    pub fn sequenced(
+        message: &ScannerMessage,
+        key: &[u8],
+        sequence: u64,
+    ) -> Result<Self, WrapError> {
+        let nonce = uuid::Uuid::new_v4().as_bytes().to_vec();
+        let message = rmp_serde::to_vec(message).map_err(|_| WrapError::Encode)?;
+        let hash = sign(key, &nonce, &message, sequence)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        Ok(ScannerWrapped::Hashed {
+            nonce,
+            message,
+            hash,
+            sequence,
+        })
+    }
+
+    // Sequence is trustworthy only after open verified the signature
+    pub fn sequence(&self) -> u64 {
+        match self {
+            ScannerWrapped::Hashed { sequence, .. } => *sequence,
+            _ => 0,
+        }
+    }
+
+    // Decode a datagram, bare legacy ScannerMessage is returned as Plain
+    pub fn from_slice(data: &[u8]) -> Result<Self, WrapError> {
+        if let Ok(wrapped) = rmp_serde::from_slice::<ScannerWrapped>(data) {
+            return Ok(wrapped);
+        }
+
+        rmp_serde::from_slice::<ScannerMessage>(data).map_err(|_| WrapError::Decode)?;
+        Ok(ScannerWrapped::Plain(data.to_vec()))
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, WrapError> {
+        rmp_serde::to_vec(self).map_err(|_| WrapError::Encode)
+    }
+
+    pub fn is_plain(&self) -> bool {
+        matches!(self, ScannerWrapped::Plain(..))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, ScannerWrapped::Encrypted { .. })
+    }
+
+    // Seal this envelope for the recipient public key
+    pub fn encrypt(&self, recipient: &[u8]) -> Result<Self, WrapError> {
+        let recipient = PublicKey::from(key32(recipient)?);
+        let secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+        let pub_key = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&recipient);
+
+        let encrypted = cipher(shared.as_bytes(), pub_key.as_bytes(), recipient.as_bytes())
+            .encrypt(&Default::default(), self.to_vec()?.as_slice())
+            .map_err(|_| WrapError::Crypto)?;
+
+        Ok(ScannerWrapped::Encrypted {
+            encrypted,
+            pub_key: pub_key.as_bytes().to_vec(),
+        })
+    }
+
+    // Open sealed envelope with the recipient secret key, other envelopes are returned as they are
+    pub fn decrypt(self, secret: &[u8]) -> Result<Self, WrapError> {
+        if let ScannerWrapped::Encrypted { encrypted, pub_key } = &self {
+            let secret = StaticSecret::from(key32(secret)?);
+            let recipient = PublicKey::from(&secret);
+            let shared = secret.diffie_hellman(&PublicKey::from(key32(pub_key)?));
+
+            let data = cipher(shared.as_bytes(), pub_key, recipient.as_bytes())
+                .decrypt(&Default::default(), encrypted.as_slice())
+                .map_err(|_| WrapError::Crypto)?;
+
+            // Nested encryption is not allowed
+            return match rmp_serde::from_slice(&data).map_err(|_| WrapError::Decode)? {
+                ScannerWrapped::Encrypted { .. } => Err(WrapError::Unsupported),
+                wrapped => Ok(wrapped),
+            };
+        }
+
+        Ok(self)
+    }
+
+    // Decode inner message without any verification
+    pub fn peek(&self) -> Result<ScannerMessage, WrapError> {
+        match self {
+            ScannerWrapped::Plain(message) | ScannerWrapped::Hashed { message, .. } => {
+                rmp_serde::from_slice(message).map_err(|_| WrapError::Decode)
+            }
+            ScannerWrapped::Encrypted { .. } => Err(WrapError::Unsupported),
+        }
+    }
+
+    // Verify the envelope and return the inner message
+    pub fn open(&self, key: Option<&[u8]>) -> Result<ScannerMessage, WrapError> {
+        if let ScannerWrapped::Hashed {
+            nonce,
+            message,
+            hash,
+            sequence,
+        } = self
+        {
+            let key = key.ok_or(WrapError::MissingKey)?;
+            if *sequence == 0 && nonce.len() != NONCE_SIZE {
+                return Err(WrapError::Signature);
+            }
+            sign(key, nonce, message, *sequence)
+                .verify_slice(hash)
+                .map_err(|_| WrapError::Signature)?;
+        }
+
+        self.peek()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::scanner::{ScanDevice, ScannerContent};
+
+    fn message() -> ScannerMessage {
+        ScannerMessage {
+            uuid: uuid::Uuid::new_v4(),
+            content: ScannerContent::ScanResult(ScanDevice {
+                mac: vec![1, 2, 3, 4, 5, 6],
+                rssi: -60,
+                data: vec![2, 1, 6],
+                age: 0,
+                timestamp: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn legacy_message_is_plain() {
+        let msg = message();
+        let wrapped = ScannerWrapped::from_slice(&rmp_serde::to_vec(&msg).unwrap()).unwrap();
+
+        assert!(wrapped.is_plain());
+        assert_eq!(wrapped.open(None), Ok(msg));
+    }
+
+    #[test]
+    fn hashed_round_trip() {
+        let msg = message();
+        let data = ScannerWrapped::hashed(&msg, b"secret")
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let wrapped = ScannerWrapped::from_slice(&data).unwrap();
+
+        assert_eq!(wrapped.open(Some(b"secret")), Ok(msg));
+        assert_eq!(wrapped.open(Some(b"other")), Err(WrapError::Signature));
+        assert_eq!(wrapped.open(None), Err(WrapError::MissingKey));
+    }
+
+    #[test]
+    fn sequenced_round_trip() {
+        let msg = message();
+        let data = ScannerWrapped::sequenced(&msg, b"secret", 42)
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let wrapped = ScannerWrapped::from_slice(&data).unwrap();
+
+        assert_eq!(wrapped.sequence(), 42);
+        assert_eq!(wrapped.open(Some(b"secret")), Ok(msg));
+
+        // Sequence is covered by the signature
+        let mut forged = wrapped.clone();
+        if let ScannerWrapped::Hashed { sequence, .. } = &mut forged {
+            *sequence = 43;
+        }
+        assert_eq!(forged.open(Some(b"secret")), Err(WrapError::Signature));
+    }
+
+    #[test]
+    fn sequenced_is_not_unsequenced() {
+        let wrapped = ScannerWrapped::sequenced(&message(), b"secret", 42).unwrap();
+        let ScannerWrapped::Hashed {
+            nonce,
+            message,
+            hash,
+            ..
+        } = wrapped
+        else {
+            panic!("Signed envelope expected");
+        };
+
+        // Same signed bytes split into an unsequenced nonce and a message with trailing bytes
+        let mut signed = SEQUENCED.to_vec();
+        signed.extend((nonce.len() as u32).to_be_bytes());
+        signed.extend(&nonce);
+        signed.extend((message.len() as u32).to_be_bytes());
+        signed.extend(&message);
+        signed.extend(42u64.to_be_bytes());
+        for split in [NONCE_SIZE, SEQUENCED.len() + 4 + nonce.len() + 4] {
+            let forged = ScannerWrapped::Hashed {
+                nonce: signed[..split].to_vec(),
+                message: signed[split..].to_vec(),
+                hash: hash.clone(),
+                sequence: 0,
+            };
+            assert!(forged.open(Some(b"secret")).is_err());
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let msg = message();
+        let secret = secret_key();
+        let public = public_key(&secret).unwrap();
+
+        let inner = ScannerWrapped::hashed(&msg, b"secret").unwrap();
+        let data = inner.encrypt(&public).unwrap().to_vec().unwrap();
+        let wrapped = ScannerWrapped::from_slice(&data).unwrap();
+        assert!(wrapped.is_encrypted());
+        assert_eq!(wrapped.peek(), Err(WrapError::Unsupported));
+
+        let opened = wrapped.decrypt(&secret).unwrap();
+        assert_eq!(opened, inner);
+        assert_eq!(opened.open(Some(b"secret")), Ok(msg));
+    }
+
+    #[test]
+    fn encrypted_with_other_key() {
+        let inner = ScannerWrapped::plain(&message()).unwrap();
+        let wrapped = inner.encrypt(&public_key(&secret_key()).unwrap()).unwrap();
+
+        assert_eq!(wrapped.decrypt(&secret_key()), Err(WrapError::Crypto));
+    }
+
+    #[test]
+    fn encrypted_tampered() {
+        let secret = secret_key();
+        let inner = ScannerWrapped::plain(&message()).unwrap();
+        let mut wrapped = inner.encrypt(&public_key(&secret).unwrap()).unwrap();
+
+        if let ScannerWrapped::Encrypted { encrypted, .. } = &mut wrapped {
+            encrypted[0] ^= 1;
+        }
+        assert_eq!(wrapped.decrypt(&secret), Err(WrapError::Crypto));
+    }
+}